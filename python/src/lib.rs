@@ -0,0 +1,207 @@
+//! Python bindings for loading, scoring and editing graphs.
+//!
+//! ```python
+//! import aminograph
+//!
+//! directory = aminograph.WorkingDirectory("results/example")
+//! graph = directory.graph()
+//! print(graph.probability(), graph.full_stats()["classification"])
+//!
+//! hypothesis = graph.copy()
+//! hypothesis.apply_moves(["add-edge:N3-N7"])
+//! print(hypothesis.probability() - graph.probability())
+//! ```
+//!
+//! Probabilities are returned as log2 values, as they are written to
+//! `stats.json`.
+
+use aminograph_core::prelude::Serialize;
+use aminograph_core::search_log::MoveRecord;
+use aminograph_core::{compression, optimization, GraphMove, WorkingDirectory};
+use numpy::{PyArray2, ToPyArray};
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use std::path::PathBuf;
+
+fn value_error(error: impl std::fmt::Display) -> PyErr {
+    PyValueError::new_err(error.to_string())
+}
+
+// converted through json so that python sees the same fields as stats.json
+fn to_python<'py>(py: Python<'py>, value: &impl Serialize) -> PyResult<Bound<'py, PyAny>> {
+    let text = serde_json::to_string(value).map_err(value_error)?;
+    py.import("json")?.call_method1("loads", (text,))
+}
+
+/// The model and alignment of a directory written by `aminograph infer`.
+#[pyclass(name = "WorkingDirectory", frozen)]
+struct PyWorkingDirectory {
+    // graphs borrow the model and alignment, and python may keep a graph
+    // alive after the directory is gone, so it is never freed
+    directory: &'static WorkingDirectory,
+}
+
+#[pymethods]
+impl PyWorkingDirectory {
+    #[new]
+    fn new(path: PathBuf) -> PyResult<Self> {
+        let directory = WorkingDirectory::open(&path).map_err(value_error)?;
+        Ok(PyWorkingDirectory {
+            directory: Box::leak(Box::new(directory)),
+        })
+    }
+
+    #[getter]
+    fn path(&self) -> PathBuf {
+        self.directory.path.clone()
+    }
+
+    #[getter]
+    fn sequence_ids(&self) -> Vec<String> {
+        self.directory
+            .alignment
+            .sequence_ids
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    /// Loads the saved graph with its fitted parameter and site rates.
+    fn graph(&self) -> PyResult<PyGraph> {
+        Ok(PyGraph {
+            graph: self.directory.graph().map_err(value_error)?,
+            directory: self.directory,
+        })
+    }
+
+    /// Loads a graph in the format of `graph.json`, given as a dict.
+    fn graph_from_exported(&self, py: Python<'_>, exported: Bound<'_, PyAny>) -> PyResult<PyGraph> {
+        let text: String = py
+            .import("json")?
+            .call_method1("dumps", (exported,))?
+            .extract()?;
+        let exported = serde_json::from_str(&text).map_err(value_error)?;
+        let parameter = self.directory.parameter().map_err(value_error)?;
+        let mut graph = aminograph_core::Graph::from_exported(
+            &self.directory.model,
+            &self.directory.alignment,
+            parameter,
+            &exported,
+        )
+        .map_err(value_error)?;
+        graph.set_site_rates(
+            aminograph_core::amino_acids::read_site_rates(&self.directory.path)
+                .map_err(value_error)?,
+        );
+        Ok(PyGraph {
+            graph,
+            directory: self.directory,
+        })
+    }
+}
+
+/// A graph over the model and alignment of a working directory.
+#[pyclass(name = "Graph")]
+struct PyGraph {
+    graph: aminograph_core::Graph<'static>,
+    directory: &'static WorkingDirectory,
+}
+
+#[pymethods]
+impl PyGraph {
+    fn copy(&self) -> PyGraph {
+        PyGraph {
+            graph: self.graph.clone(),
+            directory: self.directory,
+        }
+    }
+
+    fn probability(&mut self) -> f64 {
+        self.graph.probability().log2().raw()
+    }
+
+    fn prior(&mut self) -> f64 {
+        self.graph.prior().log2().raw()
+    }
+
+    fn likelihood(&mut self) -> f64 {
+        self.graph.likelihood().log2().raw()
+    }
+
+    #[getter]
+    fn parameter(&self) -> f64 {
+        self.graph.parameter().raw()
+    }
+
+    /// The scores and counts written to `stats.json`, as a dict.
+    fn full_stats<'py>(&mut self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        to_python(py, &self.graph.full_stats())
+    }
+
+    /// Counts of inferred substitutions, indexed by source and destination
+    /// residue.
+    fn transitions<'py>(&mut self, py: Python<'py>) -> Bound<'py, PyArray2<i32>> {
+        self.graph.ensure_clean();
+        let transitions = &self.graph.stats.transitions;
+        numpy::ndarray::Array2::from_shape_fn((20, 20), |(row, column)| transitions[(row, column)])
+            .to_pyarray(py)
+    }
+
+    /// The graph in the format of `graph.json`, as a dict.
+    fn exported<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        to_python(py, &self.graph.exported())
+    }
+
+    /// Applies moves written as for `aminograph apply-move`, such as
+    /// `add-edge:N3-N7` or `set-amino-acid:N12@40=K` with alignment columns.
+    /// The graph is left unchanged if any of them cannot be applied.
+    fn apply_moves(&mut self, moves: Vec<String>) -> PyResult<()> {
+        let mut graph = self.graph.clone();
+        for text in &moves {
+            let the_move = text
+                .parse::<GraphMove>()
+                .map_err(value_error)?
+                .with_alignment_columns(&self.directory.alignment);
+            if !the_move.is_valid(&graph) {
+                return Err(value_error(format!("{} cannot be applied", text)));
+            }
+            optimization::moves::apply_mutation(&mut graph, vec![the_move]);
+        }
+        self.graph = graph;
+        Ok(())
+    }
+
+    /// Runs the same round of hill climbing as `aminograph infer`, returning
+    /// the moves made as dicts in the format of `moves.log`.
+    fn optimize<'py>(&mut self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let made_moves = py.detach(|| optimization::optimize(&mut self.graph));
+        let records = made_moves
+            .iter()
+            .map(|made_move| MoveRecord::new(made_move, None, None, 0))
+            .collect::<Vec<_>>();
+        to_python(py, &records)
+    }
+
+    /// Saves the graph into a directory which `WorkingDirectory` and the
+    /// `aminograph` subcommands can open.
+    #[pyo3(signature = (path, compress=false))]
+    fn save(&self, path: PathBuf, compress: bool) -> PyResult<()> {
+        let save = || -> aminograph_core::prelude::Result<()> {
+            std::fs::create_dir_all(&path)?;
+            if path.canonicalize()? != self.directory.path.canonicalize()? {
+                for name in &["matrix.paml", "alignment.fasta"] {
+                    compression::decompress(&self.directory.path.join(name), &path.join(name))?;
+                }
+            }
+            WorkingDirectory::save(&path, &self.graph, compress)
+        };
+        save().map_err(value_error)
+    }
+}
+
+#[pymodule]
+fn aminograph(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_class::<PyWorkingDirectory>()?;
+    module.add_class::<PyGraph>()?;
+    Ok(())
+}
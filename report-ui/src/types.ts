@@ -2,7 +2,12 @@ export type Node = {
 	kind: "leaf" | "other" | "root",
 	sequence_id: string | null;
 	amino_acids: string;
-	parents: string[]
+	parents: string[];
+	// only present once the graph has been bootstrapped
+	support?: {
+		cluster: number | null;
+		parents: { [k: string]: number }
+	}
 }
 
 export type Report = {
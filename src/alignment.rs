@@ -1,10 +1,11 @@
 use crate::amino_acids::AminoAcid;
 use crate::amino_acids::AminoAcidMap;
 use crate::amino_acids::AminoAcidModel;
+use crate::amino_acids::RareResiduePolicy;
 use crate::fixed::{FixedIndex, FixedVec};
+use crate::formats::{AlignmentFormat, Record};
 use crate::graph::Stats;
 use crate::prelude::*;
-use seq_io::fasta::Record;
 
 define_index!(SequenceId);
 define_index!(RawPositionIndex);
@@ -19,6 +20,49 @@ pub struct Alignment {
     pub root_stats: Stats,
     pub other_stats: Stats,
     pub sequence_stats: FixedVec<SequenceId, Stats>,
+    // leaf transitions at each simple position, which other nodes add reference
+    // to reference transitions to
+    pub simple_sites: Vec<(AminoAcid, Vec<(AminoAcid, i32)>)>,
+}
+
+impl Alignment {
+    // ambiguity codes are split between the residues they could be
+    pub fn residue_counts(&self) -> [f64; 20] {
+        let mut counts = [0.0; 20];
+        for raw in self.raw_positions.values() {
+            let sequences = match raw {
+                RawPosition::Standard(sequences) => sequences,
+                RawPosition::Simple(_, sequences) => sequences,
+            };
+            for &amino_acid in sequences.values() {
+                if amino_acid.is_amino_acid() {
+                    counts[amino_acid.as_index().unwrap()] += 1.0;
+                } else if amino_acid.is_ambiguous() {
+                    let members = amino_acid.members();
+                    for member in members.iter() {
+                        counts[member.as_index().unwrap()] += 1.0 / f64::from(members.len());
+                    }
+                }
+            }
+        }
+        counts
+    }
+
+    /// The standard position for an alignment column, as counted in the FASTA
+    /// file. Columns that are not standard positions map to the first one.
+    pub fn position_index(&self, column: usize) -> PositionIndex {
+        let mut mapping = Vec::new();
+        let mut current_index = 0;
+        for position in self.raw_positions.values() {
+            if position.is_standard() {
+                mapping.push(current_index);
+                current_index += 1;
+            } else {
+                mapping.push(0);
+            }
+        }
+        PositionIndex(mapping[column])
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -39,7 +83,7 @@ impl RawPosition {
             .exactly_one()
             .ok();
 
-        if let Some(repeated) = repeated_amino_acid {
+        if let Some(repeated) = repeated_amino_acid.filter(|x| !x.is_ambiguous()) {
             if distinct_amino_acids <= 2
             /*  && (!sequences.values().any(|&x| x == AminoAcid::Gap) || AminoAcid::Gap == repeated)*/
             {
@@ -66,14 +110,23 @@ impl PositionData {
     fn new(sequences: FixedVec<SequenceId, AminoAcid>) -> PositionData {
         let mut counts = AminoAcidMap::from_fn(|_| 0);
         for &amino_acid in sequences.values() {
-            if amino_acid.is_amino_acid() || amino_acid == AminoAcid::Gap {
+            if amino_acid != AminoAcid::Unknown {
                 counts[amino_acid] += 1;
             }
         }
 
+        // ambiguity codes support each of the residues they could be
         let candidates = counts
             .iter()
-            .filter(|x| *x.1 > 1)
+            .filter(|x| !x.0.is_ambiguous())
+            .filter(|&(amino_acid, &count)| {
+                let ambiguous: i32 = counts
+                    .iter()
+                    .filter(|x| x.0.is_ambiguous() && x.0.members().contains(amino_acid))
+                    .map(|x| *x.1)
+                    .sum();
+                count + ambiguous > 1
+            })
             .map(|x| x.0)
             .collect_vec();
 
@@ -85,25 +138,110 @@ impl PositionData {
     }
 }
 
+/// Reads a FASTA alignment, scoring the columns that need no search against `model`.
 pub fn read_alignment(read: impl std::io::Read, model: &AminoAcidModel) -> Result<Alignment> {
-    let mut reader = seq_io::fasta::Reader::new(read);
+    let records = crate::formats::read_records(read, AlignmentFormat::Fasta)?;
+    build_alignment(&records, model)
+}
+
+pub fn validate_records(records: &[Record], allow_only_gaps: bool) -> Result<()> {
+    let mut problems = Vec::new();
+
+    if records.is_empty() {
+        problems.push("alignment contains no sequences".to_owned());
+    }
+
+    let mut seen = FnvHashMap::default();
+    for record in records {
+        let location = format!("line {}: sequence {}", record.line, record.id);
+
+        if record.id.is_empty() {
+            problems.push(format!("line {}: sequence has no name", record.line));
+        } else if let Some(first_line) = seen.insert(record.id.as_str(), record.line) {
+            problems.push(format!(
+                "{} duplicates the name used on line {}",
+                location, first_line
+            ));
+        }
+
+        let expected = &records[0];
+        if record.sequence.len() != expected.sequence.len() {
+            problems.push(format!(
+                "{} has {} columns, but {} has {}",
+                location,
+                record.sequence.len(),
+                expected.id,
+                expected.sequence.len()
+            ));
+        }
+
+        let mut illegal: indexmap::IndexMap<u8, (usize, usize)> = indexmap::IndexMap::new();
+        let mut residues = 0;
+        for (column, &letter) in record.sequence.iter().enumerate() {
+            match AminoAcid::from_u8(letter) {
+                Ok(amino_acid) => {
+                    if amino_acid.is_amino_acid() || amino_acid.is_ambiguous() {
+                        residues += 1;
+                    }
+                }
+                Err(_) => illegal.entry(letter).or_insert((column + 1, 0)).1 += 1,
+            }
+        }
+        for (letter, (column, count)) in illegal {
+            problems.push(format!(
+                "{} has illegal character {:?} at column {}{}",
+                location,
+                char::from(letter),
+                column,
+                if count > 1 {
+                    format!(" ({} occurrences)", count)
+                } else {
+                    String::new()
+                }
+            ));
+        }
+        if record.sequence.is_empty() {
+            problems.push(format!("{} is empty", location));
+        } else if residues == 0 && !allow_only_gaps {
+            problems.push(format!("{} contains only gaps", location));
+        }
+    }
+
+    if !problems.is_empty() {
+        bail!(
+            "alignment has {} problem{}:\n  {}",
+            problems.len(),
+            if problems.len() == 1 { "" } else { "s" },
+            problems.join("\n  ")
+        );
+    }
+    Ok(())
+}
 
-    let records = itertools::process_results(reader.records(), |records| {
-        records.into_iter().collect_vec()
-    })?;
+pub fn normalize_records(records: &mut [Record], policy: RareResiduePolicy) -> Result<()> {
+    for record in records {
+        for letter in &mut record.sequence {
+            *letter = AminoAcid::from_u8_with_policy(*letter, policy)?.as_u8();
+        }
+    }
+    Ok(())
+}
+
+/// Builds an alignment from records read in any supported format.
+pub fn build_alignment(records: &[Record], model: &AminoAcidModel) -> Result<Alignment> {
+    // sequences without residues are fine once inside a working directory
+    validate_records(records, true)?;
 
     let sequence_ids = FixedVec::<SequenceId, _>::from_raw(
-        records
-            .iter()
-            .map(|record| record.id().map(|x| x.to_owned()))
-            .collect::<Result<Vec<_>, _>>()?,
+        records.iter().map(|record| record.id.clone()).collect(),
     );
 
     let sequences = FixedVec::from_raw(
         records
             .iter()
-            .map(|seq| {
-                seq.seq
+            .map(|record| {
+                record
+                    .sequence
                     .iter()
                     .copied()
                     .map(AminoAcid::from_u8)
@@ -113,7 +251,7 @@ pub fn read_alignment(read: impl std::io::Read, model: &AminoAcidModel) -> Resul
     );
 
     let raw_positions = FixedVec::from_raw(
-        (0..records[0].seq.len())
+        (0..records[0].sequence.len())
             .map(|index| RawPosition::new(sequences.make_vec(|_, sequence| sequence[index]), index))
             .collect(),
     );
@@ -131,6 +269,7 @@ pub fn read_alignment(read: impl std::io::Read, model: &AminoAcidModel) -> Resul
     let mut root_stats = Stats::default();
     let mut other_stats = Stats::default();
     let mut sequence_stats = sequence_ids.make_vec(|_, _| Stats::default());
+    let mut simple_sites = Vec::new();
 
     for raw in raw_positions.values() {
         match raw {
@@ -138,6 +277,7 @@ pub fn read_alignment(read: impl std::io::Read, model: &AminoAcidModel) -> Resul
             RawPosition::Simple(reference, sequences) => {
                 if *reference == AminoAcid::Gap {
                     for (sequence_id, &amino_acid) in sequences.iter() {
+                        let amino_acid = model.resolve(amino_acid, *reference);
                         let sequence_stats = &mut sequence_stats[sequence_id];
                         if amino_acid != *reference && amino_acid != AminoAcid::Unknown {
                             sequence_stats.inserts.record(true);
@@ -152,12 +292,15 @@ pub fn read_alignment(read: impl std::io::Read, model: &AminoAcidModel) -> Resul
                     other_stats.record_transition(*reference, *reference);
                     other_stats.deletes.record(false);
 
+                    let mut transitions = Vec::new();
                     for (sequence_id, &amino_acid) in sequences.iter() {
+                        let amino_acid = model.resolve(amino_acid, *reference);
                         let sequence_stats = &mut sequence_stats[sequence_id];
                         if amino_acid == *reference {
                             sequence_stats.inserts.record(false);
                             sequence_stats.record_transition(*reference, amino_acid);
                             sequence_stats.deletes.record(false);
+                            transitions.push(amino_acid);
                         } else if amino_acid == AminoAcid::Gap {
                             sequence_stats.inserts.record(false);
                             sequence_stats.deletes.record(true);
@@ -165,8 +308,18 @@ pub fn read_alignment(read: impl std::io::Read, model: &AminoAcidModel) -> Resul
                             sequence_stats.inserts.record(false);
                             sequence_stats.record_transition(*reference, amino_acid);
                             sequence_stats.deletes.record(false);
+                            transitions.push(amino_acid);
                         }
                     }
+                    simple_sites.push((
+                        *reference,
+                        transitions
+                            .into_iter()
+                            .sorted()
+                            .dedup_with_count()
+                            .map(|(count, amino_acid)| (amino_acid, count as i32))
+                            .collect(),
+                    ));
                 }
             }
         }
@@ -179,5 +332,66 @@ pub fn read_alignment(read: impl std::io::Read, model: &AminoAcidModel) -> Resul
         root_stats,
         other_stats,
         sequence_stats,
+        simple_sites,
     })
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn record(id: &str, line: usize, sequence: &str) -> Record {
+        Record {
+            id: id.to_owned(),
+            line,
+            sequence: sequence.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_ambiguous_majority() {
+        let model = crate::amino_acids::read_paml_matrix(std::io::Cursor::new(include_bytes!(
+            "./BLOSUM62.paml"
+        )))
+        .unwrap();
+        let alignment = read_alignment(
+            std::io::Cursor::new(">a\nBZA\n>b\nBZR\n>c\nBZA\n>d\nNQR\n>e\nDEA\n"),
+            &model,
+        )
+        .unwrap();
+        assert!(alignment
+            .positions
+            .values()
+            .all(|position| position.candidates.iter().all(|x| !x.is_ambiguous())));
+
+        let mut graph = Graph::new(&model, &alignment);
+        assert!(graph.probability() > Log::zero());
+    }
+
+    #[test]
+    fn test_validate_records() {
+        assert!(validate_records(&[record("a", 1, "AR-N"), record("b", 3, "ARXN")], false).is_ok());
+        assert!(validate_records(&[record("a", 1, "BZ-B")], false).is_ok());
+
+        let error = validate_records(&[], false).unwrap_err().to_string();
+        assert!(error.contains("no sequences"), "{}", error);
+
+        let error = validate_records(
+            &[
+                record("a", 1, "AR-N"),
+                record("b", 3, "AR-"),
+                record("a", 5, "A#R#"),
+                record("c", 7, "----"),
+            ],
+            false,
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(error.starts_with("alignment has 4 problems"), "{}", error);
+        assert!(error.contains("line 3: sequence b has 3 columns, but a has 4"));
+        assert!(error.contains("line 5: sequence a duplicates the name used on line 1"));
+        assert!(error
+            .contains("line 5: sequence a has illegal character '#' at column 2 (2 occurrences)"));
+        assert!(error.contains("line 7: sequence c contains only gaps"));
+    }
+}
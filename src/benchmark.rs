@@ -0,0 +1,163 @@
+//! How well the search recovers graphs that alignments were simulated from.
+
+use crate::clusters::{clusters, Cluster, ExportedNodes};
+use crate::compare::compare;
+use crate::prelude::*;
+use std::collections::BTreeSet;
+use std::io::Write;
+
+pub const BENCHMARK_JSON: &str = "benchmark.json";
+pub const BENCHMARK_CSV: &str = "benchmark.csv";
+
+/// The edges into nodes with several parents, as child cluster then parent
+/// cluster.
+pub fn reticulate_edges(nodes: &ExportedNodes) -> BTreeSet<(Cluster, Cluster)> {
+    let clusters = clusters(nodes);
+    nodes
+        .iter()
+        .filter(|(_, node)| node.parents.len() > 1)
+        .flat_map(|(name, node)| {
+            let clusters = &clusters;
+            node.parents
+                .iter()
+                .map(move |parent| (clusters[name].clone(), clusters[parent].clone()))
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Recovery {
+    pub distance: f64,
+    // absent when there are no reticulate edges to take a fraction of
+    pub reticulation_precision: Option<f64>,
+    pub reticulation_recall: Option<f64>,
+}
+
+impl Recovery {
+    pub fn new(truth: &ExportedNodes, inferred: &ExportedNodes) -> Result<Recovery> {
+        let true_edges = reticulate_edges(truth);
+        let inferred_edges = reticulate_edges(inferred);
+        let recovered = true_edges.intersection(&inferred_edges).count();
+        let fraction = |total: usize| match total {
+            0 => None,
+            _ => Some(recovered as f64 / total as f64),
+        };
+        Ok(Recovery {
+            distance: compare(truth, inferred)?.distance,
+            reticulation_precision: fraction(inferred_edges.len()),
+            reticulation_recall: fraction(true_edges.len()),
+        })
+    }
+}
+
+/// One simulated dataset and what the search made of it.
+#[derive(Debug, Clone, Serialize)]
+pub struct BenchmarkRecord {
+    pub dataset: String,
+    pub sequences: usize,
+    pub columns: usize,
+    pub parameter: f64,
+    pub extra_edges: usize,
+    pub replicate: usize,
+    pub seconds: f64,
+    pub true_bits: f64,
+    pub inferred_bits: f64,
+    #[serde(flatten)]
+    pub recovery: Recovery,
+}
+
+pub fn write_csv(records: &[BenchmarkRecord], mut write: impl Write) -> Result<()> {
+    let optional = |value: Option<f64>| value.map(|value| value.to_string()).unwrap_or_default();
+    writeln!(
+        write,
+        "dataset,sequences,columns,parameter,extra_edges,replicate,seconds,true_bits,inferred_bits,distance,reticulation_precision,reticulation_recall"
+    )?;
+    for record in records {
+        writeln!(
+            write,
+            "{},{},{},{},{},{},{:.3},{:.2},{:.2},{:.4},{},{}",
+            record.dataset,
+            record.sequences,
+            record.columns,
+            record.parameter,
+            record.extra_edges,
+            record.replicate,
+            record.seconds,
+            record.true_bits,
+            record.inferred_bits,
+            record.recovery.distance,
+            optional(record.recovery.reticulation_precision),
+            optional(record.recovery.reticulation_recall)
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn nodes(text: &str) -> ExportedNodes {
+        serde_json::from_str(text).unwrap()
+    }
+
+    #[test]
+    fn test_recovery() {
+        // a is a hybrid of the root and of its ancestor with c in the first,
+        // and of the root and of its ancestor with b in the second
+        let truth = nodes(
+            r#"{
+                "N0": {"kind": "root", "sequence_id": null, "parents": [], "amino_acids": "A"},
+                "N1": {"kind": "leaf", "sequence_id": "a", "parents": ["N0", "N4"], "amino_acids": "A"},
+                "N2": {"kind": "leaf", "sequence_id": "b", "parents": ["N0"], "amino_acids": "A"},
+                "N3": {"kind": "leaf", "sequence_id": "c", "parents": ["N4"], "amino_acids": "A"},
+                "N4": {"kind": "other", "sequence_id": null, "parents": ["N0"], "amino_acids": "A"}
+            }"#,
+        );
+        let inferred = nodes(
+            r#"{
+                "N0": {"kind": "root", "sequence_id": null, "parents": [], "amino_acids": "A"},
+                "N1": {"kind": "leaf", "sequence_id": "a", "parents": ["N0", "N4"], "amino_acids": "A"},
+                "N2": {"kind": "leaf", "sequence_id": "b", "parents": ["N4"], "amino_acids": "A"},
+                "N3": {"kind": "leaf", "sequence_id": "c", "parents": ["N0"], "amino_acids": "A"},
+                "N4": {"kind": "other", "sequence_id": null, "parents": ["N0"], "amino_acids": "A"}
+            }"#,
+        );
+        assert_eq!(reticulate_edges(&truth).len(), 2);
+
+        let recovery = Recovery::new(&truth, &truth).unwrap();
+        assert_eq!(recovery.distance, 0.0);
+        assert_eq!(recovery.reticulation_precision, Some(1.0));
+        assert_eq!(recovery.reticulation_recall, Some(1.0));
+
+        // only a's edge to the root is recovered
+        let recovery = Recovery::new(&truth, &inferred).unwrap();
+        assert_eq!(recovery.reticulation_precision, Some(0.5));
+        assert_eq!(recovery.reticulation_recall, Some(0.5));
+        assert!(recovery.distance > 0.0);
+
+        let mut records = vec![BenchmarkRecord {
+            dataset: "n3".to_string(),
+            sequences: 3,
+            columns: 1,
+            parameter: 0.005,
+            extra_edges: 1,
+            replicate: 0,
+            seconds: 0.5,
+            true_bits: 10.0,
+            inferred_bits: 9.5,
+            recovery,
+        }];
+        records[0].recovery.reticulation_recall = None;
+        let mut written = Vec::new();
+        write_csv(&records, &mut written).unwrap();
+        let written = String::from_utf8(written).unwrap();
+        assert_eq!(
+            written.lines().nth(1).unwrap(),
+            format!(
+                "n3,3,1,0.005,1,0,0.500,10.00,9.50,{:.4},0.5,",
+                records[0].recovery.distance
+            )
+        );
+    }
+}
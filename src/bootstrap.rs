@@ -0,0 +1,87 @@
+//! Resampled alignments for measuring how well the columns support the
+//! clusters and edges of an inferred graph.
+
+use crate::clusters::Frequencies;
+use crate::formats::Record;
+use crate::prelude::*;
+use rand::seq::index;
+
+pub const BOOTSTRAP_FILE: &str = "bootstrap.json";
+pub const REPLICATES_LOG: &str = "bootstrap-replicates.log";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Resampling {
+    // as many columns as the alignment has, drawn with replacement
+    Bootstrap,
+    // the fraction of the columns to drop
+    Jackknife(f64),
+}
+
+impl Resampling {
+    /// The columns of a replicate, in alignment order.
+    pub fn columns(self, count: usize, random: &mut Random) -> Vec<usize> {
+        match self {
+            Resampling::Bootstrap => (0..count)
+                .map(|_| random.gen_range(0..count))
+                .sorted()
+                .collect(),
+            Resampling::Jackknife(fraction) => {
+                let kept = count - (count as f64 * fraction).round() as usize;
+                index::sample(random, count, kept)
+                    .into_iter()
+                    .sorted()
+                    .collect()
+            }
+        }
+    }
+}
+
+/// The records restricted to the given columns.
+pub fn resample(records: &[Record], columns: &[usize]) -> Vec<Record> {
+    records
+        .iter()
+        .map(|record| Record {
+            id: record.id.clone(),
+            line: record.line,
+            sequence: columns
+                .iter()
+                .map(|&column| record.sequence[column])
+                .collect(),
+        })
+        .collect()
+}
+
+/// Written to `bootstrap.json` beside the graph it was run for.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Bootstrap {
+    pub resampling: Resampling,
+    #[serde(flatten)]
+    pub frequencies: Frequencies,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_resampling() {
+        let mut random = Random::seed_from_u64(7);
+        let columns = Resampling::Bootstrap.columns(10, &mut random);
+        assert_eq!(columns.len(), 10);
+        assert!(columns.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert!(columns.iter().all(|&column| column < 10));
+
+        let columns = Resampling::Jackknife(0.3).columns(10, &mut random);
+        assert_eq!(columns.len(), 7);
+        assert!(columns.windows(2).all(|pair| pair[0] < pair[1]));
+
+        let records = vec![Record {
+            id: "a".to_string(),
+            line: 1,
+            sequence: b"ACDEF".to_vec(),
+        }];
+        assert_eq!(resample(&records, &[0, 0, 3])[0].sequence, b"AAE".to_vec());
+    }
+}
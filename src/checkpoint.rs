@@ -0,0 +1,244 @@
+use crate::amino_acids::{AminoAcidModel, SiteRates};
+use crate::compression;
+use crate::graph::ExportedNode;
+use crate::optimization::moves::MoveLog;
+use crate::prelude::*;
+use crate::search_log;
+use std::path::{Path, PathBuf};
+
+pub type Bucket<'a> = (Graph<'a>, Vec<MoveLog>);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Stage {
+    Infer,
+    ExpandSearch,
+}
+
+impl Stage {
+    // each stage has its own file, so a checkpoint left behind by the other
+    // stage is never resumed
+    fn checkpoint_path(self, path: &Path) -> PathBuf {
+        path.join(match self {
+            Stage::Infer => "checkpoint-infer.json",
+            Stage::ExpandSearch => "checkpoint-expand-search.json",
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CheckpointGraph {
+    parameter: R64,
+    #[serde(default)]
+    rates: SiteRates,
+    nodes: indexmap::IndexMap<String, ExportedNode>,
+}
+
+impl CheckpointGraph {
+    pub fn new(graph: &Graph) -> Self {
+        CheckpointGraph {
+            parameter: graph.parameter(),
+            rates: graph.site_rates(),
+            nodes: graph.exported(),
+        }
+    }
+
+    pub fn restore<'a>(
+        &self,
+        amino_acid_model: &'a AminoAcidModel,
+        alignment: &'a Alignment,
+    ) -> Result<Graph<'a>> {
+        let mut graph =
+            Graph::from_exported(amino_acid_model, alignment, self.parameter, &self.nodes)?;
+        graph.set_site_rates(self.rates);
+        Ok(graph)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub completed_rounds: u32,
+    pub random: Random,
+    pub moves_log_length: u64,
+    pub rounds_log_length: u64,
+    pub graph: CheckpointGraph,
+    pub buckets: Vec<(CheckpointGraph, Vec<MoveLog>)>,
+    // the moves.log record each bucket branched from, see MoveRecord::base
+    #[serde(default)]
+    pub bucket_bases: Vec<usize>,
+}
+
+impl Checkpoint {
+    pub fn new(
+        completed_rounds: u32,
+        random: &Random,
+        moves: &std::fs::File,
+        rounds_log: &std::fs::File,
+        graph: &Graph,
+        buckets: &[Bucket],
+        bucket_bases: &[usize],
+    ) -> Result<Checkpoint> {
+        Ok(Checkpoint {
+            completed_rounds,
+            random: random.clone(),
+            moves_log_length: moves.metadata()?.len(),
+            rounds_log_length: rounds_log.metadata()?.len(),
+            graph: CheckpointGraph::new(graph),
+            buckets: buckets
+                .iter()
+                .map(|(bucket, made_moves)| (CheckpointGraph::new(bucket), made_moves.clone()))
+                .collect(),
+            bucket_bases: bucket_bases.to_vec(),
+        })
+    }
+
+    // checkpoints from before bases were recorded branch every bucket from
+    // the start of the log
+    pub fn bucket_bases(&self, count: usize) -> Vec<usize> {
+        if self.bucket_bases.len() == count {
+            self.bucket_bases.clone()
+        } else {
+            vec![0; count]
+        }
+    }
+
+    pub fn exists(path: &Path, stage: Stage) -> bool {
+        compression::exists(&stage.checkpoint_path(path))
+    }
+
+    pub fn load(path: &Path, stage: Stage) -> Result<Option<Checkpoint>> {
+        if !Checkpoint::exists(path, stage) {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_reader(compression::open(
+            &stage.checkpoint_path(path),
+        )?)?))
+    }
+
+    pub fn remove(path: &Path, stage: Stage) -> Result<()> {
+        compression::remove(&stage.checkpoint_path(path))
+    }
+
+    pub fn save(&self, path: &Path, stage: Stage, compress: bool) -> Result<()> {
+        compression::write_json(&stage.checkpoint_path(path), self, compress)
+    }
+
+    pub fn reopen_logs(&self, path: &Path) -> Result<(std::fs::File, std::fs::File)> {
+        // anything logged after the checkpoint will be logged again on resume
+        let reopen = |name: &str, length: u64| -> Result<std::fs::File> {
+            let file = std::fs::OpenOptions::new()
+                .append(true)
+                .open(path.join(name))?;
+            file.set_len(length)?;
+            Ok(file)
+        };
+        Ok((
+            reopen(search_log::MOVES_LOG, self.moves_log_length)?,
+            reopen(search_log::ROUNDS_LOG, self.rounds_log_length)?,
+        ))
+    }
+
+    pub fn restore<'a>(
+        &self,
+        amino_acid_model: &'a AminoAcidModel,
+        alignment: &'a Alignment,
+    ) -> Result<(Graph<'a>, Vec<Bucket<'a>>)> {
+        let graph = self.graph.restore(amino_acid_model, alignment)?;
+        let buckets = self
+            .buckets
+            .iter()
+            .map(|(bucket, made_moves)| {
+                Ok((
+                    bucket.restore(amino_acid_model, alignment)?,
+                    made_moves.clone(),
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok((graph, buckets))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::{RngCore, SeedableRng};
+
+    #[test]
+    fn test_checkpoint_round_trip() -> Result<()> {
+        let directory =
+            std::env::temp_dir().join(format!("aminograph-checkpoint-{}", std::process::id()));
+        std::fs::create_dir_all(&directory)?;
+        let moves = std::fs::File::create(directory.join("moves.log"))?;
+        let rounds_log = std::fs::File::create(directory.join("rounds.log"))?;
+
+        let model = crate::amino_acids::read_paml_matrix(std::io::Cursor::new(include_bytes!(
+            "./BLOSUM62.paml"
+        )))?;
+        let alignment = crate::alignment::read_alignment(
+            std::io::Cursor::new(">a\nAWTKC\n>b\nAWSRC\n>c\nCWSRD\n>d\nCFSRD\n>e\nAFTKC\n"),
+            &model,
+        )?;
+
+        let mut random = Random::seed_from_u64(7);
+        let mut graph = Graph::new(&model, &alignment);
+        crate::optimization::moves::shuffle(&mut graph, &mut random, 3);
+        let buckets = (0..2)
+            .map(|_| {
+                let mut bucket = graph.clone();
+                let made_moves = crate::optimization::moves::shuffle(&mut bucket, &mut random, 3);
+                (bucket, made_moves)
+            })
+            .collect_vec();
+
+        Checkpoint::new(3, &random, &moves, &rounds_log, &graph, &buckets, &[4, 5])?.save(
+            &directory,
+            Stage::Infer,
+            true,
+        )?;
+
+        assert!(Checkpoint::load(&directory, Stage::ExpandSearch)?.is_none());
+        let checkpoint = Checkpoint::load(&directory, Stage::Infer)?.unwrap();
+        assert_eq!(checkpoint.completed_rounds, 3);
+        assert_eq!(checkpoint.bucket_bases, vec![4, 5]);
+
+        let (mut restored, restored_buckets) = checkpoint.restore(&model, &alignment)?;
+        assert_eq!(
+            serde_json::to_string(&restored.exported())?,
+            serde_json::to_string(&graph.exported())?
+        );
+        assert_eq!(restored.probability(), graph.probability());
+        for ((mut bucket, made_moves), (mut restored, restored_moves)) in
+            buckets.into_iter().zip(restored_buckets)
+        {
+            assert_eq!(restored.probability(), bucket.probability());
+            assert_eq!(
+                serde_json::to_string(&restored_moves)?,
+                serde_json::to_string(&made_moves)?
+            );
+        }
+
+        let mut restored_random = checkpoint.random.clone();
+        assert_eq!(restored_random.next_u64(), random.next_u64());
+
+        // continuing from the restored state makes the same moves
+        let made_moves = crate::optimization::moves::shuffle(&mut graph, &mut random, 5);
+        let restored_moves =
+            crate::optimization::moves::shuffle(&mut restored, &mut restored_random, 5);
+        assert_eq!(
+            serde_json::to_string(&restored_moves)?,
+            serde_json::to_string(&made_moves)?
+        );
+        crate::optimization::moves::optimize(&mut graph);
+        crate::optimization::moves::optimize(&mut restored);
+        assert_eq!(
+            serde_json::to_string(&restored.exported())?,
+            serde_json::to_string(&graph.exported())?
+        );
+
+        Checkpoint::remove(&directory, Stage::Infer)?;
+        assert!(!Checkpoint::exists(&directory, Stage::Infer));
+
+        std::fs::remove_dir_all(&directory)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,241 @@
+//! Clusters identify nodes across graphs over the same sequences: the
+//! cluster of a node is the set of sequences it is an ancestor of.
+
+use crate::graph::{ExportedNode, ExportedNodeKind};
+use crate::prelude::*;
+use std::collections::BTreeSet;
+
+pub type Cluster = BTreeSet<String>;
+
+pub type ExportedNodes = indexmap::IndexMap<String, ExportedNode>;
+
+/// The cluster of every node, keyed by node name.
+pub fn clusters(nodes: &ExportedNodes) -> indexmap::IndexMap<String, Cluster> {
+    let mut children: FnvHashMap<&str, Vec<&str>> = FnvHashMap::default();
+    for (name, node) in nodes {
+        for parent in &node.parents {
+            children.entry(parent).or_default().push(name);
+        }
+    }
+
+    fn visit<'n>(
+        name: &'n str,
+        nodes: &'n ExportedNodes,
+        children: &FnvHashMap<&'n str, Vec<&'n str>>,
+        found: &mut FnvHashMap<&'n str, Cluster>,
+    ) {
+        if found.contains_key(name) {
+            return;
+        }
+        let mut cluster = Cluster::new();
+        if let Some(sequence_id) = &nodes[name].sequence_id {
+            cluster.insert(sequence_id.clone());
+        }
+        for &child in children.get(name).into_iter().flatten() {
+            visit(child, nodes, children, found);
+            cluster.extend(found[child].iter().cloned());
+        }
+        found.insert(name, cluster);
+    }
+
+    let mut found = FnvHashMap::default();
+    for name in nodes.keys() {
+        visit(name, nodes, &children, &mut found);
+    }
+    nodes
+        .keys()
+        .map(|name| (name.clone(), found.remove(name.as_str()).unwrap()))
+        .collect()
+}
+
+/// The clusters of the ancestors of a graph and the edges between the
+/// clusters of its nodes, which is what two graphs over the same sequences can
+/// be compared by.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Topology {
+    pub clusters: BTreeSet<Cluster>,
+    // child cluster, then parent cluster
+    pub edges: BTreeSet<(Cluster, Cluster)>,
+}
+
+impl Topology {
+    pub fn new(nodes: &ExportedNodes) -> Topology {
+        let clusters = clusters(nodes);
+        let mut topology = Topology::default();
+        for (name, node) in nodes {
+            if node.kind != ExportedNodeKind::Leaf {
+                topology.clusters.insert(clusters[name].clone());
+            }
+            for parent in &node.parents {
+                topology
+                    .edges
+                    .insert((clusters[name].clone(), clusters[parent].clone()));
+            }
+        }
+        topology
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClusterFrequency {
+    pub leaves: Cluster,
+    pub frequency: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EdgeFrequency {
+    pub child: Cluster,
+    pub parent: Cluster,
+    pub frequency: f64,
+}
+
+/// The fraction of a set of graphs in which each cluster of an ancestor, and
+/// each edge between clusters, appears.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Frequencies {
+    pub graphs: usize,
+    pub clusters: Vec<ClusterFrequency>,
+    pub edges: Vec<EdgeFrequency>,
+}
+
+impl Frequencies {
+    pub fn new<'n>(graphs: impl IntoIterator<Item = &'n ExportedNodes>) -> Frequencies {
+        let mut count = 0;
+        let mut cluster_counts: indexmap::IndexMap<Cluster, usize> = Default::default();
+        let mut edge_counts: indexmap::IndexMap<(Cluster, Cluster), usize> = Default::default();
+        for nodes in graphs {
+            count += 1;
+            let topology = Topology::new(nodes);
+            for cluster in topology.clusters {
+                *cluster_counts.entry(cluster).or_default() += 1;
+            }
+            for edge in topology.edges {
+                *edge_counts.entry(edge).or_default() += 1;
+            }
+        }
+
+        let frequency = |seen: usize| seen as f64 / count as f64;
+        Frequencies {
+            graphs: count,
+            clusters: cluster_counts
+                .into_iter()
+                .map(|(leaves, seen)| ClusterFrequency {
+                    leaves,
+                    frequency: frequency(seen),
+                })
+                .sorted_by(|lhs, rhs| rhs.frequency.partial_cmp(&lhs.frequency).unwrap())
+                .collect(),
+            edges: edge_counts
+                .into_iter()
+                .map(|((child, parent), seen)| EdgeFrequency {
+                    child,
+                    parent,
+                    frequency: frequency(seen),
+                })
+                .sorted_by(|lhs, rhs| rhs.frequency.partial_cmp(&lhs.frequency).unwrap())
+                .collect(),
+        }
+    }
+
+    /// The frequency of each ancestor of the graph and of each of its edges,
+    /// keyed by node name.
+    pub fn for_nodes(&self, nodes: &ExportedNodes) -> indexmap::IndexMap<String, NodeSupport> {
+        let clusters_by_leaves: FnvHashMap<&Cluster, f64> = self
+            .clusters
+            .iter()
+            .map(|cluster| (&cluster.leaves, cluster.frequency))
+            .collect();
+        let edges_by_leaves: FnvHashMap<(&Cluster, &Cluster), f64> = self
+            .edges
+            .iter()
+            .map(|edge| ((&edge.child, &edge.parent), edge.frequency))
+            .collect();
+
+        let clusters = clusters(nodes);
+        nodes
+            .iter()
+            .map(|(name, node)| {
+                let cluster = &clusters[name];
+                let support = NodeSupport {
+                    cluster: match node.kind {
+                        ExportedNodeKind::Leaf => None,
+                        _ => Some(clusters_by_leaves.get(cluster).copied().unwrap_or(0.0)),
+                    },
+                    parents: node
+                        .parents
+                        .iter()
+                        .map(|parent| {
+                            let frequency = edges_by_leaves
+                                .get(&(cluster, &clusters[parent]))
+                                .copied()
+                                .unwrap_or(0.0);
+                            (parent.clone(), frequency)
+                        })
+                        .collect(),
+                };
+                (name.clone(), support)
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct NodeSupport {
+    // absent for leaves, which are in every graph
+    pub cluster: Option<f64>,
+    pub parents: indexmap::IndexMap<String, f64>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn nodes(text: &str) -> ExportedNodes {
+        serde_json::from_str(text).unwrap()
+    }
+
+    #[test]
+    fn test_frequencies() {
+        // ((a,b),c) and ((a,c),b), where the second has a as a hybrid of both
+        let first = nodes(
+            r#"{
+                "N0": {"kind": "root", "sequence_id": null, "parents": [], "amino_acids": ""},
+                "N1": {"kind": "leaf", "sequence_id": "a", "parents": ["N4"], "amino_acids": ""},
+                "N2": {"kind": "leaf", "sequence_id": "b", "parents": ["N4"], "amino_acids": ""},
+                "N3": {"kind": "leaf", "sequence_id": "c", "parents": ["N0"], "amino_acids": ""},
+                "N4": {"kind": "other", "sequence_id": null, "parents": ["N0"], "amino_acids": ""}
+            }"#,
+        );
+        let second = nodes(
+            r#"{
+                "N0": {"kind": "root", "sequence_id": null, "parents": [], "amino_acids": ""},
+                "N1": {"kind": "leaf", "sequence_id": "a", "parents": ["N5", "N0"], "amino_acids": ""},
+                "N2": {"kind": "leaf", "sequence_id": "b", "parents": ["N0"], "amino_acids": ""},
+                "N3": {"kind": "leaf", "sequence_id": "c", "parents": ["N5"], "amino_acids": ""},
+                "N5": {"kind": "other", "sequence_id": null, "parents": ["N0"], "amino_acids": ""}
+            }"#,
+        );
+
+        let found = clusters(&second);
+        assert_eq!(found["N0"].len(), 3);
+        assert_eq!(
+            found["N5"],
+            ["a", "c"].iter().map(|x| x.to_string()).collect()
+        );
+
+        let frequencies = Frequencies::new(vec![&first, &second]);
+        assert_eq!(frequencies.graphs, 2);
+        let support = frequencies.for_nodes(&first);
+        assert_eq!(support["N0"].cluster, Some(1.0));
+        assert_eq!(support["N4"].cluster, Some(0.5));
+        assert_eq!(support["N1"].cluster, None);
+        assert_eq!(support["N1"].parents["N4"], 0.5);
+        assert_eq!(support["N3"].parents["N0"], 0.5);
+        assert_eq!(support["N4"].parents["N0"], 0.5);
+
+        let support = frequencies.for_nodes(&second);
+        // a hangs from the root in the second graph only
+        assert_eq!(support["N1"].parents["N0"], 0.5);
+        assert_eq!(support["N2"].parents["N0"], 0.5);
+    }
+}
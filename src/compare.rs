@@ -0,0 +1,207 @@
+//! Differences between two graphs over the same sequences. Nodes are matched
+//! by their clusters, so the names the graphs give them don't matter.
+
+use crate::clusters::{clusters, Cluster, ExportedNodes, Topology};
+use crate::graph::ExportedNodeKind;
+use crate::prelude::*;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Edge {
+    pub child: Cluster,
+    pub parent: Cluster,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ResidueDifference {
+    pub column: usize,
+    pub cluster: Cluster,
+    pub lhs_node: String,
+    pub rhs_node: String,
+    pub lhs: char,
+    pub rhs: char,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Comparison {
+    pub shared_clusters: Vec<Cluster>,
+    pub lhs_clusters: Vec<Cluster>,
+    pub rhs_clusters: Vec<Cluster>,
+    pub shared_edges: usize,
+    pub lhs_edges: Vec<Edge>,
+    pub rhs_edges: Vec<Edge>,
+    // of the ancestors both graphs have, by column
+    pub residues: Vec<ResidueDifference>,
+    // the clusters and edges in only one graph, as a fraction of those in
+    // either, so 0 for the same topology and 1 when nothing is shared
+    pub distance: f64,
+}
+
+// the first ancestor in graph order stands for a cluster several ancestors share
+fn ancestors_by_cluster<'n>(
+    nodes: &'n ExportedNodes,
+    clusters: &'n indexmap::IndexMap<String, Cluster>,
+) -> FnvHashMap<&'n Cluster, &'n str> {
+    let mut ancestors = FnvHashMap::default();
+    for (name, node) in nodes {
+        if node.kind != ExportedNodeKind::Leaf {
+            ancestors.entry(&clusters[name]).or_insert(name.as_str());
+        }
+    }
+    ancestors
+}
+
+fn leaves(nodes: &ExportedNodes) -> Cluster {
+    nodes
+        .values()
+        .filter_map(|node| node.sequence_id.clone())
+        .collect()
+}
+
+pub fn compare(lhs: &ExportedNodes, rhs: &ExportedNodes) -> Result<Comparison> {
+    if leaves(lhs) != leaves(rhs) {
+        bail!("The graphs are not over the same sequences");
+    }
+    let columns = |nodes: &ExportedNodes| {
+        nodes
+            .values()
+            .map(|node| node.amino_acids.chars().count())
+            .next()
+    };
+    if columns(lhs) != columns(rhs) {
+        bail!("The graphs are not over alignments of the same length");
+    }
+
+    let lhs_topology = Topology::new(lhs);
+    let rhs_topology = Topology::new(rhs);
+    let only = |this: &Topology, other: &Topology| {
+        (
+            this.clusters
+                .difference(&other.clusters)
+                .cloned()
+                .collect_vec(),
+            this.edges
+                .difference(&other.edges)
+                .map(|(child, parent)| Edge {
+                    child: child.clone(),
+                    parent: parent.clone(),
+                })
+                .collect_vec(),
+        )
+    };
+    let (lhs_clusters, lhs_edges) = only(&lhs_topology, &rhs_topology);
+    let (rhs_clusters, rhs_edges) = only(&rhs_topology, &lhs_topology);
+    let shared_clusters = lhs_topology
+        .clusters
+        .intersection(&rhs_topology.clusters)
+        .cloned()
+        .collect_vec();
+
+    let lhs_clusters_by_node = clusters(lhs);
+    let rhs_clusters_by_node = clusters(rhs);
+    let lhs_ancestors = ancestors_by_cluster(lhs, &lhs_clusters_by_node);
+    let rhs_ancestors = ancestors_by_cluster(rhs, &rhs_clusters_by_node);
+    let residues = shared_clusters
+        .iter()
+        .flat_map(|cluster| {
+            let lhs_node = lhs_ancestors[cluster];
+            let rhs_node = rhs_ancestors[cluster];
+            lhs[lhs_node]
+                .amino_acids
+                .chars()
+                .zip(rhs[rhs_node].amino_acids.chars())
+                .enumerate()
+                .filter(|(_, (lhs, rhs))| lhs != rhs)
+                .map(move |(column, (lhs, rhs))| ResidueDifference {
+                    column,
+                    cluster: cluster.clone(),
+                    lhs_node: lhs_node.to_string(),
+                    rhs_node: rhs_node.to_string(),
+                    lhs,
+                    rhs,
+                })
+        })
+        .sorted_by_key(|difference| difference.column)
+        .collect_vec();
+
+    let total = lhs_topology.clusters.len()
+        + rhs_topology.clusters.len()
+        + lhs_topology.edges.len()
+        + rhs_topology.edges.len();
+    let unique = lhs_clusters.len() + rhs_clusters.len() + lhs_edges.len() + rhs_edges.len();
+    Ok(Comparison {
+        shared_edges: lhs_topology.edges.len() - lhs_edges.len(),
+        distance: if total == 0 {
+            0.0
+        } else {
+            unique as f64 / total as f64
+        },
+        shared_clusters,
+        lhs_clusters,
+        rhs_clusters,
+        lhs_edges,
+        rhs_edges,
+        residues,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn nodes(text: &str) -> ExportedNodes {
+        serde_json::from_str(text).unwrap()
+    }
+
+    #[test]
+    fn test_compare() {
+        // ((a,b),c) and ((a,c),b), where the second has a as a hybrid of both
+        let first = nodes(
+            r#"{
+                "N0": {"kind": "root", "sequence_id": null, "parents": [], "amino_acids": "AC"},
+                "N1": {"kind": "leaf", "sequence_id": "a", "parents": ["N4"], "amino_acids": "AC"},
+                "N2": {"kind": "leaf", "sequence_id": "b", "parents": ["N4"], "amino_acids": "AC"},
+                "N3": {"kind": "leaf", "sequence_id": "c", "parents": ["N0"], "amino_acids": "AD"},
+                "N4": {"kind": "other", "sequence_id": null, "parents": ["N0"], "amino_acids": "AC"}
+            }"#,
+        );
+        let mut second = nodes(
+            r#"{
+                "R": {"kind": "root", "sequence_id": null, "parents": [], "amino_acids": "AD"},
+                "A": {"kind": "leaf", "sequence_id": "a", "parents": ["AC", "R"], "amino_acids": "AC"},
+                "B": {"kind": "leaf", "sequence_id": "b", "parents": ["R"], "amino_acids": "AC"},
+                "C": {"kind": "leaf", "sequence_id": "c", "parents": ["AC"], "amino_acids": "AD"},
+                "AC": {"kind": "other", "sequence_id": null, "parents": ["R"], "amino_acids": "AD"}
+            }"#,
+        );
+
+        let comparison = compare(&first, &first).unwrap();
+        assert_eq!(comparison.distance, 0.0);
+        assert_eq!(comparison.shared_clusters.len(), 2);
+        assert_eq!(comparison.shared_edges, 4);
+        assert!(comparison.residues.is_empty());
+
+        let comparison = compare(&first, &second).unwrap();
+        let cluster = |leaves: &[&str]| leaves.iter().map(|x| x.to_string()).collect::<Cluster>();
+        assert_eq!(comparison.shared_clusters, vec![cluster(&["a", "b", "c"])]);
+        assert_eq!(comparison.lhs_clusters, vec![cluster(&["a", "b"])]);
+        assert_eq!(comparison.rhs_clusters, vec![cluster(&["a", "c"])]);
+        assert_eq!(comparison.shared_edges, 0);
+        assert_eq!(comparison.lhs_edges.len(), 4);
+        assert_eq!(comparison.rhs_edges.len(), 5);
+        assert_eq!(comparison.distance, 11.0 / 13.0);
+        assert_eq!(
+            comparison.residues,
+            vec![ResidueDifference {
+                column: 1,
+                cluster: cluster(&["a", "b", "c"]),
+                lhs_node: "N0".to_string(),
+                rhs_node: "R".to_string(),
+                lhs: 'C',
+                rhs: 'D',
+            }]
+        );
+
+        second.shift_remove("B");
+        assert!(compare(&first, &second).is_err());
+    }
+}
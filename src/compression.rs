@@ -0,0 +1,179 @@
+use crate::graph::ExportedNode;
+use crate::prelude::*;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+const GRAPH_FILE: &str = "graph.json";
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const BZIP2_MAGIC: &[u8] = b"BZh";
+
+pub fn is_compression_extension(extension: &str) -> bool {
+    extension.eq_ignore_ascii_case("gz") || extension.eq_ignore_ascii_case("bz2")
+}
+
+fn with_extension(path: &Path, extension: &str) -> PathBuf {
+    let mut extended = path.as_os_str().to_owned();
+    extended.push(".");
+    extended.push(extension);
+    PathBuf::from(extended)
+}
+
+fn variants(path: &Path) -> [PathBuf; 3] {
+    [
+        path.to_owned(),
+        with_extension(path, "gz"),
+        with_extension(path, "bz2"),
+    ]
+}
+
+fn resolve(path: &Path) -> PathBuf {
+    variants(path)
+        .iter()
+        .find(|variant| variant.exists())
+        .cloned()
+        .unwrap_or_else(|| path.to_owned())
+}
+
+pub fn exists(path: &Path) -> bool {
+    resolve(path).exists()
+}
+
+pub fn remove(path: &Path) -> Result<()> {
+    for variant in &variants(path) {
+        if variant.exists() {
+            std::fs::remove_file(variant)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn open(path: &Path) -> Result<Box<dyn BufRead>> {
+    let path = resolve(path);
+    let mut file = BufReader::new(
+        std::fs::File::open(&path).map_err(|error| eyre!("{}: {}", path.display(), error))?,
+    );
+    let header = file.fill_buf()?;
+    Ok(if header.starts_with(GZIP_MAGIC) {
+        Box::new(BufReader::new(flate2::bufread::MultiGzDecoder::new(file)))
+    } else if header.starts_with(BZIP2_MAGIC) {
+        Box::new(BufReader::new(bzip2::bufread::MultiBzDecoder::new(file)))
+    } else {
+        Box::new(file)
+    })
+}
+
+pub fn decompress(source: &Path, target: &Path) -> Result<()> {
+    let mut output = std::io::BufWriter::new(std::fs::File::create(target)?);
+    std::io::copy(&mut open(source)?, &mut output)?;
+    output.flush()?;
+    Ok(())
+}
+
+pub fn graph_compressed(path: &Path) -> bool {
+    let path = path.join(GRAPH_FILE);
+    resolve(&path) != path
+}
+
+pub fn read_graph(path: &Path) -> Result<indexmap::IndexMap<String, ExportedNode>> {
+    Ok(serde_json::from_reader(open(&path.join(GRAPH_FILE))?)?)
+}
+
+pub fn write_graph(path: &Path, graph: &Graph, compress: bool) -> Result<()> {
+    write_json(&path.join(GRAPH_FILE), &graph.exported(), compress)
+}
+
+pub fn write_json(path: &Path, value: &impl Serialize, compress: bool) -> Result<()> {
+    let target = if compress {
+        with_extension(path, "gz")
+    } else {
+        path.to_owned()
+    };
+
+    // write to the side and rename so that being killed mid-write leaves
+    // the previous file intact
+    let partial_path = with_extension(&target, "partial");
+    let output = std::io::BufWriter::new(std::fs::File::create(&partial_path)?);
+    let output = if compress {
+        let mut output = flate2::write::GzEncoder::new(output, flate2::Compression::default());
+        serde_json::to_writer_pretty(&mut output, value)?;
+        output.finish()?
+    } else {
+        let mut output = output;
+        serde_json::to_writer_pretty(&mut output, value)?;
+        output
+    };
+    output.into_inner()?.sync_all()?;
+
+    std::fs::rename(partial_path, &target)?;
+
+    // otherwise a later read could pick up an outdated file
+    for stale in &variants(path) {
+        if *stale != target && stale.exists() {
+            std::fs::remove_file(stale)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_open_detects_compression() {
+        let directory =
+            std::env::temp_dir().join(format!("aminograph-compression-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let text = b">s0\nARND\n>s1\nARNE\n";
+
+        std::fs::write(directory.join("plain.fasta"), text).unwrap();
+
+        let mut encoder = flate2::write::GzEncoder::new(
+            std::fs::File::create(directory.join("alignment.fasta.gz")).unwrap(),
+            flate2::Compression::default(),
+        );
+        encoder.write_all(text).unwrap();
+        encoder.finish().unwrap();
+
+        let mut encoder = bzip2::write::BzEncoder::new(
+            std::fs::File::create(directory.join("other.fasta")).unwrap(),
+            bzip2::Compression::default(),
+        );
+        encoder.write_all(text).unwrap();
+        encoder.finish().unwrap();
+
+        for name in &["plain.fasta", "alignment.fasta", "other.fasta"] {
+            let mut contents = Vec::new();
+            open(&directory.join(name))
+                .unwrap()
+                .read_to_end(&mut contents)
+                .unwrap();
+            assert_eq!(contents, text, "{}", name);
+        }
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_write_json_replaces_other_compression() {
+        let directory =
+            std::env::temp_dir().join(format!("aminograph-write-json-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("value.json");
+        std::fs::write(directory.join("value.json.bz2"), b"stale").unwrap();
+
+        write_json(&path, &vec![1, 2, 3], true).unwrap();
+        assert!(!directory.join("value.json.bz2").exists());
+        assert!(!directory.join("value.json.gz.partial").exists());
+        let value: Vec<i32> = serde_json::from_reader(open(&path).unwrap()).unwrap();
+        assert_eq!(value, vec![1, 2, 3]);
+
+        write_json(&path, &vec![4], false).unwrap();
+        assert!(!directory.join("value.json.gz").exists());
+        let value: Vec<i32> = serde_json::from_reader(open(&path).unwrap()).unwrap();
+        assert_eq!(value, vec![4]);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
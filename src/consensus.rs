@@ -0,0 +1,130 @@
+//! Consensus of graphs over the same sequences, such as runs with different
+//! seeds or the samples of a chain.
+
+use crate::amino_acids::AminoAcidModel;
+use crate::clusters::{Cluster, Frequencies};
+use crate::optimization::analyze_amino_acids;
+use crate::prelude::*;
+
+pub const CONSENSUS_FILE: &str = "consensus.json";
+
+/// Written to `consensus.json` beside the consensus graph.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Consensus {
+    pub threshold: f64,
+    #[serde(flatten)]
+    pub frequencies: Frequencies,
+}
+
+impl<'a> Graph<'a> {
+    /// Builds the graph with every cluster and every edge between clusters
+    /// found in at least `threshold` of the graphs the frequencies were
+    /// counted over. A node none of its edges are kept for hangs from the
+    /// smallest kept cluster holding it, as in a majority-rule tree.
+    pub fn from_consensus(
+        amino_acid_model: &'a AminoAcidModel,
+        alignment: &'a Alignment,
+        frequencies: &Frequencies,
+        threshold: f64,
+    ) -> Result<Self> {
+        let mut graph = Graph::new(amino_acid_model, alignment);
+        let root = graph.root();
+
+        let mut nodes: indexmap::IndexMap<Cluster, NodeId> = Default::default();
+        for node_id in graph.node_ids().collect_vec() {
+            if let NodeKind::Leaf(sequence_id) = graph[node_id].kind {
+                let leaves = std::iter::once(alignment.sequence_ids[sequence_id].clone()).collect();
+                nodes.insert(leaves, node_id);
+                graph.remove_edge(node_id, root);
+            }
+        }
+        let all: Cluster = nodes.keys().flatten().cloned().collect();
+        if let Some(cluster) = frequencies
+            .clusters
+            .iter()
+            .find(|cluster| !cluster.leaves.is_subset(&all))
+        {
+            bail!(
+                "Sequence {} is not in the alignment",
+                cluster.leaves.difference(&all).next().unwrap()
+            );
+        }
+        nodes.insert(all, root);
+
+        // most frequent first, so that ties for the smallest cluster holding a
+        // node go to the better supported one
+        let mut ancestors = Vec::new();
+        for cluster in &frequencies.clusters {
+            if cluster.frequency >= threshold && !nodes.contains_key(&cluster.leaves) {
+                let node_id = graph.create_node(root);
+                nodes.insert(cluster.leaves.clone(), node_id);
+                ancestors.push((cluster.leaves.len(), node_id));
+            }
+        }
+
+        for edge in &frequencies.edges {
+            if edge.frequency >= threshold && edge.child.len() < edge.parent.len() {
+                if let (Some(&child), Some(&parent)) =
+                    (nodes.get(&edge.child), nodes.get(&edge.parent))
+                {
+                    graph.add_edge(child, parent);
+                }
+            }
+        }
+        for (leaves, &node_id) in &nodes {
+            if node_id != root && graph[node_id].parents.is_empty() {
+                let (_, &parent) = nodes
+                    .iter()
+                    .filter(|(other, _)| other.len() > leaves.len() && other.is_superset(leaves))
+                    .min_by_key(|(other, _)| other.len())
+                    .unwrap();
+                graph.add_edge(node_id, parent);
+            }
+        }
+
+        // a child's cluster is smaller than its parents', so ancestral states
+        // are pushed up from the leaves
+        ancestors.sort_by_key(|&(size, _)| size);
+        for (_, node_id) in ancestors {
+            analyze_amino_acids(&mut graph, node_id, 0, &mut NullTracer);
+        }
+
+        Ok(graph)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::newick::parse_newick;
+
+    const ALIGNMENT: &[u8] = b">s1\nAWTK\n>s2\nAWSR\n>s3\nCWSR\n";
+
+    #[test]
+    fn test_from_consensus() -> Result<()> {
+        let amino_acid_model = crate::amino_acids::read_paml_matrix(std::io::Cursor::new(
+            include_bytes!("./BLOSUM62.paml"),
+        ))?;
+        let alignment =
+            crate::alignment::read_alignment(std::io::Cursor::new(ALIGNMENT), &amino_acid_model)?;
+        let graphs = ["((s1,s2),s3);", "((s1,s2),s3);", "((s1,s3),s2);"]
+            .iter()
+            .map(|tree| {
+                Ok(
+                    Graph::from_newick(&amino_acid_model, &alignment, &parse_newick(tree)?)?
+                        .exported(),
+                )
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let frequencies = Frequencies::new(&graphs);
+
+        let graph = Graph::from_consensus(&amino_acid_model, &alignment, &frequencies, 0.5)?;
+        assert_eq!(graph.newick(), "(s3,(s1,s2)N4)N0;");
+
+        // the minority cluster is kept too, and with it the edges of both
+        let graph = Graph::from_consensus(&amino_acid_model, &alignment, &frequencies, 0.3)?;
+        assert_eq!(graph.node_ids().count(), 6);
+        assert_eq!(graph.newick(), "(s2#H2,s3#H3,(s1#H1,#H2)N4,(#H1,#H3)N5)N0;");
+        Ok(())
+    }
+}
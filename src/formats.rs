@@ -0,0 +1,686 @@
+use crate::prelude::*;
+use seq_io::fasta::Record as _;
+use std::io::Read;
+use std::path::Path;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AlignmentFormat {
+    Fasta,
+    Phylip,
+    PhylipStrict,
+    Clustal,
+    Stockholm,
+    Nexus,
+}
+
+impl std::str::FromStr for AlignmentFormat {
+    type Err = color_eyre::eyre::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "fasta" => AlignmentFormat::Fasta,
+            "phylip" => AlignmentFormat::Phylip,
+            "phylip-strict" => AlignmentFormat::PhylipStrict,
+            "clustal" => AlignmentFormat::Clustal,
+            "stockholm" => AlignmentFormat::Stockholm,
+            "nexus" => AlignmentFormat::Nexus,
+            _ => bail!(
+                "Unknown alignment format {}, expected one of fasta, phylip, phylip-strict, clustal, stockholm, nexus",
+                s
+            ),
+        })
+    }
+}
+
+impl AlignmentFormat {
+    pub fn from_path(path: &Path) -> AlignmentFormat {
+        let extension_of = |path: &Path| {
+            path.extension()
+                .and_then(|extension| extension.to_str())
+                .unwrap_or("")
+                .to_ascii_lowercase()
+        };
+        let mut extension = extension_of(path);
+        if crate::compression::is_compression_extension(&extension) {
+            extension = extension_of(Path::new(path.file_stem().unwrap_or_default()));
+        }
+        match extension.as_str() {
+            "phy" | "phylip" => AlignmentFormat::Phylip,
+            "aln" | "clustal" | "clw" => AlignmentFormat::Clustal,
+            "sto" | "stk" | "stockholm" => AlignmentFormat::Stockholm,
+            "nex" | "nexus" | "nxs" => AlignmentFormat::Nexus,
+            _ => AlignmentFormat::Fasta,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub id: String,
+    pub line: usize,
+    pub sequence: Vec<u8>,
+}
+
+pub fn read_records(mut read: impl Read, format: AlignmentFormat) -> Result<Vec<Record>> {
+    if format == AlignmentFormat::Fasta {
+        return read_fasta(read);
+    }
+
+    let mut text = String::new();
+    read.read_to_string(&mut text)?;
+    match format {
+        AlignmentFormat::Fasta => unreachable!(),
+        AlignmentFormat::Phylip => read_phylip(&text, false),
+        AlignmentFormat::PhylipStrict => read_phylip(&text, true),
+        AlignmentFormat::Clustal => read_clustal(&text),
+        AlignmentFormat::Stockholm => read_stockholm(&text),
+        AlignmentFormat::Nexus => read_nexus(&text),
+    }
+}
+
+pub fn write_fasta(records: &[Record], mut write: impl std::io::Write) -> Result<()> {
+    for record in records {
+        writeln!(write, ">{}", record.id)?;
+        for chunk in record.sequence.chunks(60) {
+            write.write_all(chunk)?;
+            writeln!(write)?;
+        }
+    }
+    Ok(())
+}
+
+fn read_fasta(read: impl Read) -> Result<Vec<Record>> {
+    let mut reader = seq_io::fasta::Reader::new(read);
+    let mut records = Vec::new();
+    while let Some(record) = reader.next() {
+        let (id, sequence) = {
+            let record = record?;
+            (record.id()?.to_owned(), record.owned_seq())
+        };
+        let line = reader
+            .position()
+            .map_or(0, |position| position.line() as usize);
+        records.push(Record { id, line, sequence });
+    }
+    Ok(records)
+}
+
+fn residues(text: &str) -> impl Iterator<Item = u8> + '_ {
+    text.bytes().filter(|c| !c.is_ascii_whitespace())
+}
+
+fn numbered_lines(text: &str) -> impl Iterator<Item = (usize, &str)> {
+    text.lines()
+        .enumerate()
+        .map(|(index, line)| (index + 1, line))
+        .filter(|(_, line)| !line.trim().is_empty())
+}
+
+// for formats where each block repeats the sequence names
+struct BlockRecords {
+    records: Vec<Record>,
+    indexes: FnvHashMap<String, usize>,
+}
+
+impl BlockRecords {
+    fn new() -> Self {
+        BlockRecords {
+            records: Vec::new(),
+            indexes: FnvHashMap::default(),
+        }
+    }
+
+    fn append(&mut self, id: &str, line: usize, sequence: impl Iterator<Item = u8>) {
+        let records = &mut self.records;
+        let index = *self.indexes.entry(id.to_owned()).or_insert_with(|| {
+            records.push(Record {
+                id: id.to_owned(),
+                line,
+                sequence: Vec::new(),
+            });
+            records.len() - 1
+        });
+        self.records[index].sequence.extend(sequence);
+    }
+}
+
+fn read_phylip(text: &str, strict: bool) -> Result<Vec<Record>> {
+    let lines = numbered_lines(text).collect_vec();
+    let (header_line, header) = lines
+        .first()
+        .copied()
+        .ok_or_else(|| eyre!("PHYLIP alignment is empty"))?;
+    let header = header.split_whitespace().collect_vec();
+    let parse = |value: &str| -> Result<usize> {
+        match value.parse() {
+            Ok(0) | Err(_) => bail!(
+                "line {}: expected a positive number in the PHYLIP header, got {}",
+                header_line,
+                value
+            ),
+            Ok(value) => Ok(value),
+        }
+    };
+    let (count, length) = match header.as_slice() {
+        [count, length, ..] => (parse(count)?, parse(length)?),
+        _ => bail!(
+            "line {}: expected PHYLIP header with sequence count and length",
+            header_line
+        ),
+    };
+    let lines = &lines[1..];
+    if lines.len() < count {
+        bail!(
+            "PHYLIP header promises {} sequences but there are only {} lines",
+            count,
+            lines.len()
+        );
+    }
+
+    let named = |(line, text): (usize, &str)| -> Record {
+        let (id, sequence) = if strict {
+            let split = text
+                .char_indices()
+                .nth(10)
+                .map_or(text.len(), |(index, _)| index);
+            text.split_at(split)
+        } else {
+            let text = text.trim_start();
+            text.split_at(text.find(char::is_whitespace).unwrap_or(text.len()))
+        };
+        Record {
+            id: id.trim().to_owned(),
+            line,
+            sequence: residues(sequence).collect(),
+        }
+    };
+
+    // interleaved: the first block carries the names, later blocks follow the same order
+    let mut records = lines[..count].iter().copied().map(named).collect_vec();
+    for (index, &(_, text)) in lines[count..].iter().enumerate() {
+        records[index % count].sequence.extend(residues(text));
+    }
+    if records.iter().all(|record| record.sequence.len() == length) {
+        return Ok(records);
+    }
+
+    // sequential: each sequence may wrap onto several lines
+    let mut records = Vec::new();
+    let mut lines = lines.iter().copied();
+    while let Some(line) = lines.next() {
+        let mut record = named(line);
+        while record.sequence.len() < length {
+            match lines.next() {
+                Some((_, text)) => record.sequence.extend(residues(text)),
+                None => break,
+            }
+        }
+        records.push(record);
+    }
+
+    if records.len() != count {
+        bail!(
+            "PHYLIP header promises {} sequences but found {}",
+            count,
+            records.len()
+        );
+    }
+    for record in &records {
+        if record.sequence.len() != length {
+            bail!(
+                "line {}: sequence {} has {} residues but the PHYLIP header promises {}",
+                record.line,
+                record.id,
+                record.sequence.len(),
+                length
+            );
+        }
+    }
+    Ok(records)
+}
+
+fn read_clustal(text: &str) -> Result<Vec<Record>> {
+    let mut lines = numbered_lines(text);
+    match lines.next() {
+        Some((_, header))
+            if ["CLUSTAL", "MUSCLE", "PROBCONS"]
+                .iter()
+                .any(|prefix| header.starts_with(prefix)) => {}
+        Some((line, _)) => bail!("line {}: expected a CLUSTAL header", line),
+        None => bail!("Clustal alignment is empty"),
+    }
+
+    let mut records = BlockRecords::new();
+    for (line, text) in lines {
+        // the consensus line under each block is indented
+        if text.starts_with(char::is_whitespace) {
+            continue;
+        }
+        let parts = text.split_whitespace().collect_vec();
+        match parts.as_slice() {
+            [id, sequence] => records.append(id, line, residues(sequence)),
+            // optionally followed by the running residue count
+            [id, sequence, count] if count.parse::<usize>().is_ok() => {
+                records.append(id, line, residues(sequence))
+            }
+            _ => bail!("line {}: expected sequence name and residues", line),
+        }
+    }
+    Ok(records.records)
+}
+
+fn read_stockholm(text: &str) -> Result<Vec<Record>> {
+    let mut lines = numbered_lines(text);
+    match lines.next() {
+        Some((_, header)) if header.starts_with("# STOCKHOLM") => {}
+        Some((line, _)) => bail!("line {}: expected a # STOCKHOLM header", line),
+        None => bail!("Stockholm alignment is empty"),
+    }
+
+    let mut records = BlockRecords::new();
+    for (line, text) in lines {
+        if text.starts_with("//") {
+            break;
+        }
+        // #=GF, #=GS, #=GR and #=GC annotations
+        if text.starts_with('#') {
+            continue;
+        }
+        let parts = text.split_whitespace().collect_vec();
+        match parts.as_slice() {
+            [id, sequence] => records.append(
+                id,
+                line,
+                residues(sequence).map(|c| if c == b'.' { b'-' } else { c }),
+            ),
+            _ => bail!("line {}: expected sequence name and residues", line),
+        }
+    }
+    Ok(records.records)
+}
+
+fn nexus_tokens(text: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c.is_whitespace() {
+            continue;
+        }
+        let mut token = String::new();
+        if c == '\'' {
+            while let Some(c) = chars.next() {
+                if c == '\'' {
+                    if chars.peek() == Some(&'\'') {
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                token.push(c);
+            }
+        } else {
+            token.push(c);
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() {
+                    break;
+                }
+                token.push(c);
+                chars.next();
+            }
+        }
+        tokens.push(token);
+    }
+    tokens
+}
+
+fn nexus_settings(text: &str) -> Vec<String> {
+    text.replace('=', " = ")
+        .split(|c: char| c.is_whitespace() || c == ';')
+        .filter(|x| !x.is_empty())
+        .map(|x| x.trim_matches(|c| c == '"' || c == '\'').to_owned())
+        .collect()
+}
+
+fn nexus_setting(settings: &[String], key: &str) -> Option<String> {
+    settings
+        .windows(3)
+        .find(|x| x[0].eq_ignore_ascii_case(key) && x[1] == "=")
+        .map(|x| x[2].clone())
+}
+
+// split into commands terminated by ';' outside of quotes, each with the
+// line it starts on
+fn nexus_commands(text: &str, first_line: usize) -> Vec<(usize, &str)> {
+    let mut commands = Vec::new();
+    let mut line = first_line;
+    let mut start = (0, first_line);
+    let mut quoted = false;
+    for (index, c) in text.char_indices() {
+        match c {
+            '\'' => quoted = !quoted,
+            '\n' => line += 1,
+            ';' if !quoted => {
+                commands.push((start.1, &text[start.0..index]));
+                start = (index + 1, line);
+            }
+            _ => {}
+        }
+    }
+    if !text[start.0..].trim().is_empty() {
+        commands.push((start.1, &text[start.0..]));
+    }
+    commands
+        .into_iter()
+        .map(|(line, command)| {
+            let leading = &command[..command.len() - command.trim_start().len()];
+            (line + leading.matches('\n').count(), command.trim())
+        })
+        .collect()
+}
+
+fn read_nexus(text: &str) -> Result<Vec<Record>> {
+    // blank out comments but keep the line numbering
+    let mut depth = 0;
+    let text: String = text
+        .chars()
+        .map(|c| match c {
+            '[' => {
+                depth += 1;
+                ' '
+            }
+            ']' if depth > 0 => {
+                depth -= 1;
+                ' '
+            }
+            '\n' => '\n',
+            _ if depth > 0 => ' ',
+            _ => c,
+        })
+        .collect();
+
+    // the header may share its line with the first command
+    let is_header = |header: &str| {
+        let prefix = header.trim_start().get(..6);
+        prefix.map(|x| x.eq_ignore_ascii_case("#nexus")) == Some(true)
+    };
+    let header_line = match numbered_lines(&text).next() {
+        Some((line, header)) if is_header(header) => line,
+        Some((line, _)) => bail!("line {}: expected a #NEXUS header", line),
+        None => bail!("NEXUS file is empty"),
+    };
+    let header_end = text.len() - text.trim_start().len() + "#nexus".len();
+    let commands = nexus_commands(&text[header_end..], header_line);
+
+    let keyword = |command: &str| {
+        command
+            .split_whitespace()
+            .next()
+            .unwrap_or("")
+            .to_ascii_lowercase()
+    };
+    let mut commands = commands.into_iter().skip_while(|(_, command)| {
+        let words = command
+            .split_whitespace()
+            .map(|x| x.to_ascii_lowercase())
+            .collect_vec();
+        !matches!(
+            words.as_slice(),
+            [begin, block] if begin == "begin" && (block == "data" || block == "characters")
+        )
+    });
+    if commands.next().is_none() {
+        bail!("NEXUS file has no DATA or CHARACTERS block");
+    }
+
+    let mut settings = String::new();
+    let mut matrix = None;
+    for (line, command) in commands {
+        match keyword(command).as_str() {
+            "matrix" => {
+                matrix = Some((line, &command["matrix".len()..]));
+                break;
+            }
+            "end" | "endblock" => break,
+            _ => {
+                settings.push_str(command);
+                settings.push(' ');
+            }
+        }
+    }
+    let (matrix_line, matrix) = matrix.ok_or_else(|| eyre!("NEXUS DATA block has no MATRIX"))?;
+
+    let settings = nexus_settings(&settings);
+    let count: Option<usize> = nexus_setting(&settings, "ntax")
+        .map(|x| x.parse())
+        .transpose()?;
+    let length: usize = nexus_setting(&settings, "nchar")
+        .ok_or_else(|| eyre!("NEXUS matrix is missing NCHAR"))?
+        .parse()?;
+    let interleaved = settings
+        .iter()
+        .any(|x| x.eq_ignore_ascii_case("interleave"))
+        && !matches!(nexus_setting(&settings, "interleave"), Some(x) if x.eq_ignore_ascii_case("no"));
+    let gap = nexus_setting(&settings, "gap").and_then(|x| x.bytes().next());
+    let missing = nexus_setting(&settings, "missing")
+        .and_then(|x| x.bytes().next())
+        .unwrap_or(b'?');
+    let matchchar = nexus_setting(&settings, "matchchar").and_then(|x| x.bytes().next());
+
+    let mut records = BlockRecords::new();
+    let mut current: Option<String> = None;
+    for (index, text) in matrix.lines().enumerate() {
+        let line = matrix_line + index;
+        let mut tokens = nexus_tokens(text).into_iter();
+        if interleaved {
+            if let Some(id) = tokens.next() {
+                records.append(&id, line, tokens.flat_map(|x| x.into_bytes()));
+            }
+        } else {
+            for token in tokens {
+                match current.take() {
+                    Some(id) => {
+                        records.append(&id, line, token.bytes());
+                        if records.records[records.indexes[&id]].sequence.len() < length {
+                            current = Some(id);
+                        }
+                    }
+                    None => {
+                        records.append(&token, line, std::iter::empty());
+                        current = Some(token);
+                    }
+                }
+            }
+        }
+    }
+
+    let mut records = records.records;
+    if let Some(count) = count {
+        if records.len() != count {
+            bail!(
+                "NEXUS matrix promises {} taxa but found {}",
+                count,
+                records.len()
+            );
+        }
+    }
+
+    let first = records
+        .first()
+        .map(|x| x.sequence.clone())
+        .unwrap_or_default();
+    for record in &mut records {
+        for (index, c) in record.sequence.iter_mut().enumerate() {
+            if Some(*c) == gap {
+                *c = b'-';
+            } else if *c == missing {
+                *c = b'X';
+            } else if Some(*c) == matchchar {
+                *c = first.get(index).copied().unwrap_or(b'X');
+            }
+        }
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn read(text: &str, format: AlignmentFormat) -> Vec<(String, String)> {
+        read_records(std::io::Cursor::new(text), format)
+            .unwrap()
+            .into_iter()
+            .map(|record| (record.id, String::from_utf8(record.sequence).unwrap()))
+            .collect()
+    }
+
+    fn expected() -> Vec<(String, String)> {
+        vec![
+            ("alpha".to_owned(), "ARNDCQEG-H".to_owned()),
+            ("beta".to_owned(), "ARNECQ-GKH".to_owned()),
+        ]
+    }
+
+    #[test]
+    fn test_format_from_path() {
+        assert_eq!(
+            AlignmentFormat::from_path(Path::new("x/PF00001.sto")),
+            AlignmentFormat::Stockholm
+        );
+        assert_eq!(
+            AlignmentFormat::from_path(Path::new("mafft.ALN")),
+            AlignmentFormat::Clustal
+        );
+        assert_eq!(
+            AlignmentFormat::from_path(Path::new("pfam.nex.gz")),
+            AlignmentFormat::Nexus
+        );
+        assert_eq!(
+            AlignmentFormat::from_path(Path::new("seqs.fa")),
+            AlignmentFormat::Fasta
+        );
+    }
+
+    #[test]
+    fn test_fasta() {
+        let records = read_records(
+            std::io::Cursor::new(">alpha\nARNDC\nQEG-H\n>beta desc\nARNECQ-GKH\n"),
+            AlignmentFormat::Fasta,
+        )
+        .unwrap();
+        assert_eq!(records[0].sequence, b"ARNDCQEG-H");
+        assert_eq!(records[1].id, "beta");
+        assert_eq!(records[1].line, 4);
+    }
+
+    #[test]
+    fn test_phylip() {
+        assert_eq!(
+            read(
+                "2 10\nalpha ARNDC QEG-H\nbeta  ARNECQ-GKH\n",
+                AlignmentFormat::Phylip
+            ),
+            expected()
+        );
+        assert_eq!(
+            read(
+                "2 10\nalpha ARNDC\nbeta  ARNEC\n\nQEG-H\nQ-GKH\n",
+                AlignmentFormat::Phylip
+            ),
+            expected()
+        );
+        assert_eq!(
+            read(
+                "2 10\nalpha ARNDC\nQEG-H\nbeta  ARNECQ\n-GKH\n",
+                AlignmentFormat::Phylip
+            ),
+            expected()
+        );
+        assert_eq!(
+            read(
+                "2 10\nalpha     ARNDCQEG-H\nbeta      ARNECQ-GKH\n",
+                AlignmentFormat::PhylipStrict
+            ),
+            expected()
+        );
+        assert!(read_records(
+            std::io::Cursor::new("2 10\nalpha ARNDC\nbeta ARNECQ-GKH\n"),
+            AlignmentFormat::Phylip
+        )
+        .is_err());
+        for &(text, line) in &[
+            ("0 4\nabc ARND\n", 1),
+            ("\n1 0\nabc\n", 2),
+            ("\n2 x\nabc ARND\n", 2),
+        ] {
+            let error = read_records(std::io::Cursor::new(text), AlignmentFormat::Phylip)
+                .unwrap_err()
+                .to_string();
+            assert!(error.starts_with(&format!("line {}:", line)), "{}", error);
+        }
+    }
+
+    #[test]
+    fn test_clustal() {
+        assert_eq!(
+            read(
+                "CLUSTAL W (1.83) multiple sequence alignment\n\n\
+                 alpha      ARNDC 5\n\
+                 beta       ARNEC 5\n\
+                 \x20          ***.*\n\n\
+                 alpha      QEG-H\n\
+                 beta       Q-GKH\n",
+                AlignmentFormat::Clustal
+            ),
+            expected()
+        );
+        assert!(read_records(
+            std::io::Cursor::new("CLUSTAL W\n\nalpha ARNDC QEG-H\nbeta ARNEC Q-GKH\n"),
+            AlignmentFormat::Clustal
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_stockholm() {
+        assert_eq!(
+            read(
+                "# STOCKHOLM 1.0\n#=GF ID test\n#=GS alpha AC P00001\n\
+                 alpha ARNDC\nbeta  ARNEC\n#=GC SS_cons .....\n\n\
+                 alpha QEG.H\nbeta  Q-GKH\n//\n",
+                AlignmentFormat::Stockholm
+            ),
+            expected()
+        );
+    }
+
+    #[test]
+    fn test_nexus() {
+        assert_eq!(
+            read(
+                "#NEXUS\n[comment]\nBEGIN DATA;\n  DIMENSIONS NTAX=2 NCHAR=10;\n\
+                 FORMAT DATATYPE=PROTEIN GAP=- MISSING=? MATCHCHAR=.;\nMATRIX\n\
+                 alpha ARNDCQEG-H\n'beta' ...E..-.K.\n;\nEND;\n",
+                AlignmentFormat::Nexus
+            ),
+            expected()
+        );
+        assert_eq!(
+            read(
+                "#NEXUS\nbegin characters;\ndimensions ntax = 2 nchar = 10;\n\
+                 format datatype=protein interleave;\nmatrix\n\
+                 alpha ARNDC\nbeta ARNEC\n\nalpha QEG-H\nbeta Q-GKH;\nend;\n",
+                AlignmentFormat::Nexus
+            ),
+            expected()
+        );
+        assert_eq!(
+            read(
+                "#NEXUS BEGIN DATA; DIMENSIONS NTAX=2 NCHAR=10; FORMAT DATATYPE=PROTEIN; \
+                 MATRIX alpha ARNDCQEG-H beta ARNECQ-GKH; END;",
+                AlignmentFormat::Nexus
+            ),
+            expected()
+        );
+    }
+}
@@ -1,5 +1,5 @@
 use crate::{
-    amino_acids::{AminoAcidModel, ParameterizedAminoAcidModel},
+    amino_acids::{AminoAcidModel, ParameterizedAminoAcidModel, SiteRates, SiteTransitions},
     prelude::*,
 };
 
@@ -83,11 +83,14 @@ impl Stats {
     }
 
     fn likelihood(&self, model: &ParameterizedAminoAcidModel) -> Log {
+        self.indel_likelihood() * model.likelihood(&self.transitions)
+    }
+
+    fn indel_likelihood(&self) -> Log {
         self.insert_probability.unfix()
             * self.deletes.likelihood()
             * self.inserts.likelihood()
             * self.initial.likelihood()
-            * model.likelihood(&self.transitions)
     }
 
     fn prior(&self) -> Log {
@@ -173,15 +176,16 @@ impl Node {
         } else {
             self.amino_acids.values().for_each(|&amino_acid| {
                 let Inheritance(inherited, _) = amino_acid.inherited.unwrap();
+                // ambiguity codes only occur on leaves
+                let actual = amino_acid_model.resolve(amino_acid.amino_acid, inherited);
 
-                match (inherited, amino_acid.amino_acid) {
+                match (inherited, actual) {
                     (_, AminoAcid::Unknown) => {}
                     (AminoAcid::Gap, AminoAcid::Gap) => {}
                     (AminoAcid::Gap, _) => {
                         // insert
                         stats.inserts.record(true);
-                        stats.insert_probability *=
-                            amino_acid_model.initial(amino_acid.amino_acid);
+                        stats.insert_probability *= amino_acid_model.initial(actual);
                     }
                     (_, AminoAcid::Gap) => {
                         // delete
@@ -194,7 +198,7 @@ impl Node {
                     (_, _) => {
                         stats.deletes.record(false);
                         stats.inserts.record(false);
-                        stats.record_transition(inherited, amino_acid.amino_acid);
+                        stats.record_transition(inherited, actual);
                     }
                 }
             });
@@ -255,14 +259,15 @@ pub struct Graph<'a> {
     topological_order: Arc<TopologicalOrder>,
     dirty: bool,
     parameterized_model: Arc<ParameterizedAminoAcidModel>,
+    site_likelihood: Option<Log>,
 
     pub prior_adjustment: Option<Log>,
     pub stats: Stats,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
-enum ExportedNodeKind {
+pub enum ExportedNodeKind {
     Leaf,
     Root,
     Other,
@@ -270,13 +275,14 @@ enum ExportedNodeKind {
 
 #[derive(Serialize, Deserialize)]
 pub struct ExportedNode {
-    kind: ExportedNodeKind,
-    sequence_id: Option<String>,
-    parents: Vec<String>,
+    pub kind: ExportedNodeKind,
+    pub sequence_id: Option<String>,
+    pub parents: Vec<String>,
     pub amino_acids: String,
 }
 
 impl<'a> Graph<'a> {
+    /// Rebuilds a graph saved with [`Graph::exported`].
     pub fn from_exported(
         amino_acid_model: &'a AminoAcidModel,
         alignment: &'a Alignment,
@@ -342,6 +348,7 @@ impl<'a> Graph<'a> {
                 next_index: 0,
             }),
             parameterized_model: Arc::new(amino_acid_model.parameterize(parameter)),
+            site_likelihood: None,
             alignment,
             amino_acid_model,
             nodes,
@@ -364,6 +371,7 @@ impl<'a> Graph<'a> {
         Ok(graph)
     }
 
+    /// The nodes keyed by name, as saved in `graph.json`.
     pub fn exported(&self) -> indexmap::IndexMap<String, ExportedNode> {
         self.nodes
             .iter()
@@ -434,6 +442,7 @@ impl<'a> Graph<'a> {
         self.update_topological_order();
     }
 
+    /// A star phylogeny with every sequence a child of the root.
     pub fn new(amino_acid_model: &'a AminoAcidModel, alignment: &'a Alignment) -> Self {
         let mut nodes = Slab::new();
 
@@ -444,7 +453,13 @@ impl<'a> Graph<'a> {
             amino_acids: alignment.positions.make_vec(|_, data| NodeAminoAcid {
                 inherited: None,
                 pending: true,
-                amino_acid: data.counts.iter().max_by_key(|x| x.1).unwrap().0,
+                amino_acid: data
+                    .counts
+                    .iter()
+                    .filter(|x| x.0.as_index().is_some())
+                    .max_by_key(|x| x.1)
+                    .unwrap()
+                    .0,
                 height: 0,
             }),
             stats: None,
@@ -494,6 +509,7 @@ impl<'a> Graph<'a> {
             prior_adjustment: None,
             dirty: true,
             parameterized_model: Arc::new(amino_acid_model.parameterize(r64(1.0))),
+            site_likelihood: None,
         };
         graph.update_topological_order();
         graph
@@ -512,13 +528,76 @@ impl<'a> Graph<'a> {
     }
 
     pub fn set_parameter(&mut self, parameter: R64) {
-        self.parameterized_model = Arc::new(self.amino_acid_model.parameterize(parameter));
+        self.set_model(parameter, self.site_rates());
     }
 
     pub fn parameter(&self) -> R64 {
         self.parameterized_model.parameter
     }
 
+    pub fn set_site_rates(&mut self, rates: SiteRates) {
+        self.set_model(self.parameter(), rates);
+    }
+
+    pub fn site_rates(&self) -> SiteRates {
+        self.parameterized_model.rates
+    }
+
+    pub fn set_model(&mut self, parameter: R64, rates: SiteRates) {
+        self.parameterized_model = Arc::new(
+            self.amino_acid_model
+                .parameterize_with_rates(parameter, rates),
+        );
+        self.site_likelihood = None;
+    }
+
+    pub fn site_transitions(&mut self) -> SiteTransitions {
+        self.ensure_derived();
+        let model = self.amino_acid_model;
+
+        let mut sites = self.alignment.positions.make_vec(|_, _| Vec::new());
+        for node in self.nodes.values() {
+            if node.kind == NodeKind::Root {
+                continue;
+            }
+            for (index, amino_acid) in node.amino_acids.iter() {
+                let Inheritance(inherited, _) = amino_acid.inherited.unwrap();
+                let actual = model.resolve(amino_acid.amino_acid, inherited);
+                if inherited.is_amino_acid() && actual.is_amino_acid() {
+                    sites[index].push((inherited, actual));
+                }
+            }
+        }
+
+        let mut sites = sites
+            .into_values()
+            .map(|site| {
+                site.into_iter()
+                    .sorted()
+                    .dedup_with_count()
+                    .map(|(count, (from, to))| (from, to, count as i32))
+                    .collect_vec()
+            })
+            .collect_vec();
+
+        let other_nodes = self
+            .nodes
+            .values()
+            .filter(|node| node.kind == NodeKind::Other)
+            .count() as i32;
+        for (reference, transitions) in &self.alignment.simple_sites {
+            let mut site = transitions
+                .iter()
+                .map(|&(amino_acid, count)| (*reference, amino_acid, count))
+                .collect_vec();
+            if other_nodes > 0 {
+                site.push((*reference, *reference, other_nodes));
+            }
+            sites.push(site);
+        }
+        sites
+    }
+
     fn compute_prior_adjustment(&self) -> Log {
         let other_nodes =
             i32::try_from(self.nodes.len() - self.alignment.sequence_ids.len()).unwrap();
@@ -540,6 +619,7 @@ impl<'a> Graph<'a> {
         self.edge_count
     }
 
+    /// The posterior score searches maximize, the prior times the likelihood.
     pub fn probability(&mut self) -> Log {
         self.probability_traced(&mut NullTracer)
     }
@@ -680,13 +760,41 @@ impl<'a> Graph<'a> {
                 }
                 current = self.nodes.next_id(node_id);
             }
+            self.site_likelihood = None;
             self.dirty = false;
         }
     }
 
     pub fn likelihood(&mut self) -> Log {
         self.ensure_clean();
-        self.stats.likelihood(&self.parameterized_model)
+        if self.site_rates().is_uniform() {
+            return self.stats.likelihood(&self.parameterized_model);
+        }
+        self.stats.indel_likelihood() * self.transition_likelihood()
+    }
+
+    fn transition_likelihood(&mut self) -> Log {
+        self.ensure_clean();
+        if self.site_rates().is_uniform() {
+            return self.parameterized_model.likelihood(&self.stats.transitions);
+        }
+        if self.site_likelihood.is_none() {
+            let sites = self.site_transitions();
+            self.site_likelihood = Some(self.parameterized_model.site_likelihood(&sites));
+        }
+        self.site_likelihood.unwrap()
+    }
+
+    /// The terms whose product is the likelihood.
+    pub fn likelihood_components(&mut self) -> LikelihoodComponents {
+        self.ensure_clean();
+        LikelihoodComponents {
+            initial: self.stats.initial.likelihood(),
+            inserts: self.stats.inserts.likelihood(),
+            deletes: self.stats.deletes.likelihood(),
+            insert_residues: self.stats.insert_probability.unfix(),
+            transitions: self.transition_likelihood(),
+        }
     }
 
     pub fn prior(&mut self) -> Log {
@@ -936,6 +1044,7 @@ impl<'a> Graph<'a> {
         self.nodes[node].amino_acids[position].inherited.unwrap()
     }
 
+    /// The scores and counts written to `stats.json`.
     pub fn full_stats(&mut self) -> FullStats {
         FullStats {
             stats: self.stats,
@@ -974,15 +1083,24 @@ impl<'a> std::ops::Index<NodeId> for Graph<'a> {
     }
 }
 
+#[derive(Debug, Serialize)]
+pub struct LikelihoodComponents {
+    pub initial: Log,
+    pub inserts: Log,
+    pub deletes: Log,
+    pub insert_residues: Log,
+    pub transitions: Log,
+}
+
 #[derive(Debug, Serialize)]
 pub struct FullStats {
     #[serde(flatten)]
-    stats: Stats,
-    edge_count: u32,
-    node_count: usize,
-    leaf_count: usize,
-    probability: Log,
-    prior: Log,
-    likelihood: Log,
-    classification: &'static str,
+    pub stats: Stats,
+    pub edge_count: u32,
+    pub node_count: usize,
+    pub leaf_count: usize,
+    pub probability: Log,
+    pub prior: Log,
+    pub likelihood: Log,
+    pub classification: &'static str,
 }
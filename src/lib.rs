@@ -0,0 +1,63 @@
+//! Infers a directed acyclic graph of ancestral sequences from a protein
+//! alignment.
+//!
+//! Load a substitution model and an alignment, build a [`Graph`] over them, then
+//! search for a more probable graph:
+//!
+//! ```
+//! use aminograph::prelude::*;
+//! use aminograph::{amino_acids, optimization};
+//!
+//! # fn main() -> Result<()> {
+//! let model = amino_acids::read_paml_matrix(amino_acids::builtin_model("blosum62")?.paml)?;
+//! let alignment = read_alignment(
+//!     std::io::Cursor::new(">a\nIIDMSCNN\n>b\nIIDMSCLN\n>c\nGINMSCNN\n>d\nGINMSCNR\n"),
+//!     &model,
+//! )?;
+//!
+//! let mut graph = Graph::new(&model, &alignment);
+//! optimization::nn_join(&mut graph);
+//! optimization::optimize_parameter(&mut graph);
+//! optimization::optimize(&mut graph);
+//! println!("{:?} = {:?} * {:?}", graph.probability(), graph.prior(), graph.likelihood());
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Directories written by the `aminograph` binary can be loaded through
+//! [`WorkingDirectory`], and graphs exchanged with other tools through
+//! [`Graph::exported`], [`Graph::from_exported`] and [`Graph::from_newick`].
+
+#[macro_use]
+pub mod fixed;
+#[allow(dead_code)]
+#[macro_use]
+pub mod slab;
+
+pub mod alignment;
+pub mod amino_acids;
+pub mod benchmark;
+pub mod bootstrap;
+pub mod checkpoint;
+pub mod clusters;
+pub mod compare;
+pub mod consensus;
+pub mod compression;
+pub mod formats;
+pub mod graph;
+pub mod log;
+pub mod newick;
+pub mod optimization;
+mod order_optimize;
+pub mod prelude;
+pub mod sampling;
+pub mod search_log;
+pub mod simulate;
+pub mod trace;
+pub mod working_directory;
+
+pub use alignment::{build_alignment, read_alignment, Alignment};
+pub use amino_acids::{builtin_model, read_paml_matrix, AminoAcidModel, SiteRates};
+pub use graph::Graph;
+pub use optimization::moves::GraphMove;
+pub use working_directory::WorkingDirectory;
@@ -1,26 +1,19 @@
-mod amino_acids;
 use std::io::Write;
 
 use jemallocator::Jemalloc;
 #[global_allocator]
 static GLOBAL: Jemalloc = Jemalloc;
 
-#[macro_use]
-mod fixed;
-mod alignment;
-mod log;
-mod optimization;
-mod prelude;
-mod trace;
-#[allow(dead_code)]
-#[macro_use]
-mod slab;
-mod graph;
-mod order_optimize;
-use optimization::moves::GraphMove;
+use aminograph::{
+    alignment, amino_acids, benchmark, bootstrap, checkpoint, clusters, compare, compression,
+    consensus, formats, graph, newick, optimization, sampling, search_log, simulate, trace,
+    WorkingDirectory,
+};
+use checkpoint::{Checkpoint, Stage};
 use rand::SeedableRng;
+use search_log::{MoveRecord, RoundRecord};
 
-use crate::prelude::*;
+use aminograph::prelude::*;
 use std::path::Path;
 
 use structopt::StructOpt;
@@ -45,15 +38,231 @@ pub fn cli_init() -> Result<()> {
     Ok(())
 }
 
+#[derive(Debug, StructOpt)]
+struct SearchSchedule {
+    #[structopt(default_value = "8", long = "buckets")]
+    buckets: usize,
+    #[structopt(default_value = "7", long = "shuffle-moves")]
+    shuffle_moves: usize,
+    // wall-clock limit on the rounds, in minutes
+    #[structopt(long = "max-minutes")]
+    max_minutes: Option<f64>,
+    // stop once this many rounds have not improved the probability by more
+    // than --min-improvement bits
+    #[structopt(long = "patience")]
+    patience: Option<u32>,
+    #[structopt(default_value = "0", long = "min-improvement")]
+    min_improvement: f64,
+    // buckets anneal before climbing when a starting temperature is given,
+    // which is multiplied by --cooling every round
+    #[structopt(long = "temperature")]
+    temperature: Option<f64>,
+    #[structopt(default_value = "0.99", long = "cooling")]
+    cooling: f64,
+    #[structopt(default_value = "20", long = "annealing-steps")]
+    annealing_steps: usize,
+}
+
+impl SearchSchedule {
+    fn validate(&self) -> Result<()> {
+        if self.buckets == 0 {
+            bail!("--buckets must be at least 1");
+        }
+        if self.min_improvement < 0.0 {
+            bail!("--min-improvement cannot be negative");
+        }
+        if matches!(self.temperature, Some(temperature) if temperature <= 0.0) {
+            bail!("--temperature must be positive");
+        }
+        if self.cooling <= 0.0 || self.cooling > 1.0 {
+            bail!("--cooling must be greater than 0 and at most 1");
+        }
+        Ok(())
+    }
+
+    fn check_resumed(&self, checkpoint: &Checkpoint) -> Result<()> {
+        if checkpoint.buckets.len() != self.buckets {
+            bail!(
+                "The checkpoint was made with {} buckets, resume with --buckets {}",
+                checkpoint.buckets.len(),
+                checkpoint.buckets.len()
+            );
+        }
+        Ok(())
+    }
+
+    // each bucket anneals with its own generator so that the buckets can run
+    // in parallel, seeded from the search's generator
+    fn annealing(&self, round: u32, random: &mut Random) -> Vec<Option<(f64, u64)>> {
+        (0..self.buckets)
+            .map(|_| {
+                self.temperature.map(|temperature| {
+                    (
+                        temperature * self.cooling.powf(f64::from(round)),
+                        random.gen(),
+                    )
+                })
+            })
+            .collect()
+    }
+
+    // without --patience a minimum improvement is required over each pass
+    // through the buckets
+    fn patience(&self) -> Option<u32> {
+        match self.patience {
+            Some(patience) => Some(patience),
+            None if self.min_improvement > 0.0 => Some(self.buckets as u32),
+            None => None,
+        }
+    }
+}
+
+// a resumed search counts time and stalled rounds from where it resumed
+struct StoppingRules<'s> {
+    schedule: &'s SearchSchedule,
+    started: std::time::Instant,
+    reference: Log,
+    reference_round: u32,
+}
+
+impl<'s> StoppingRules<'s> {
+    fn new(schedule: &'s SearchSchedule, probability: Log, round: u32) -> Self {
+        StoppingRules {
+            schedule,
+            started: std::time::Instant::now(),
+            reference: probability,
+            reference_round: round,
+        }
+    }
+
+    // called with the best probability once the round has finished
+    fn check(&mut self, round: u32, probability: Log) -> Option<String> {
+        if probability.log2() - self.reference.log2() > self.schedule.min_improvement {
+            self.reference = probability;
+            self.reference_round = round + 1;
+        }
+        if let Some(patience) = self.schedule.patience() {
+            if round + 1 - self.reference_round >= patience {
+                return Some(format!(
+                    "No improvement of more than {} bits in {} rounds",
+                    self.schedule.min_improvement, patience
+                ));
+            }
+        }
+        if let Some(max_minutes) = self.schedule.max_minutes {
+            let minutes = self.started.elapsed().as_secs_f64() / 60.0;
+            if minutes >= max_minutes {
+                return Some(format!("Reached the limit of {} minutes", max_minutes));
+            }
+        }
+        None
+    }
+}
+
+#[derive(Debug, StructOpt)]
+struct SampleOptions {
+    #[structopt(default_value = "1000000", long = "iterations")]
+    iterations: u64,
+    #[structopt(default_value = "100000", long = "burn-in")]
+    burn_in: u64,
+    #[structopt(default_value = "1000", long = "thin")]
+    thin: u64,
+    #[structopt(default_value = "1337", long = "seed")]
+    seed: u64,
+    // heated chains run alongside the sampled one, at heats rising
+    // geometrically to --max-heat, and propose swaps every --swap-interval
+    // iterations
+    #[structopt(default_value = "1", long = "chains")]
+    chains: usize,
+    #[structopt(default_value = "8", long = "max-heat")]
+    max_heat: f64,
+    #[structopt(default_value = "10", long = "swap-interval")]
+    swap_interval: u64,
+}
+
+#[derive(Debug, StructOpt)]
+struct SimulateOptions {
+    // evolve down the topology of this graph.json, or the graph of this
+    // directory, instead of a random one
+    #[structopt(long = "graph")]
+    graph: Option<std::path::PathBuf>,
+    #[structopt(default_value = "16", long = "sequences")]
+    sequences: usize,
+    #[structopt(default_value = "0", long = "extra-edges")]
+    extra_edges: usize,
+    #[structopt(default_value = "300", long = "length")]
+    length: usize,
+    #[structopt(long = "paml", conflicts_with = "model")]
+    paml: Option<std::path::PathBuf>,
+    #[structopt(long = "model")]
+    model: Option<String>,
+    #[structopt(default_value = "0.005", long = "parameter")]
+    parameter: f64,
+    #[structopt(default_value = "uniform", long = "rates")]
+    rates: amino_acids::SiteRates,
+    #[structopt(default_value = "0.01", long = "insert-rate")]
+    insert_rate: f64,
+    #[structopt(default_value = "0.01", long = "delete-rate")]
+    delete_rate: f64,
+    #[structopt(default_value = "1337", long = "seed")]
+    seed: u64,
+}
+
+#[derive(Debug, StructOpt)]
+struct BenchmarkOptions {
+    // a dataset is simulated for every combination of these, --replicates
+    // times over
+    #[structopt(default_value = "8,16", long = "sequences", use_delimiter = true)]
+    sequences: Vec<usize>,
+    #[structopt(
+        default_value = "0.002,0.005",
+        long = "parameters",
+        use_delimiter = true
+    )]
+    parameters: Vec<f64>,
+    #[structopt(default_value = "0,1,2", long = "extra-edges", use_delimiter = true)]
+    extra_edges: Vec<usize>,
+    #[structopt(default_value = "1", long = "replicates")]
+    replicates: usize,
+    #[structopt(default_value = "200", long = "length")]
+    length: usize,
+    #[structopt(default_value = "50", long = "rounds")]
+    rounds: u32,
+    #[structopt(default_value = "1337", long = "seed")]
+    seed: u64,
+    #[structopt(flatten)]
+    schedule: SearchSchedule,
+}
+
 #[derive(Debug, StructOpt)]
 enum CommandLine {
     Infer {
         alignment: std::path::PathBuf,
-        #[structopt(long = "paml")]
+        #[structopt(long = "format")]
+        format: Option<formats::AlignmentFormat>,
+        #[structopt(default_value = "map", long = "rare-residues")]
+        rare_residues: amino_acids::RareResiduePolicy,
+        #[structopt(long = "paml", conflicts_with = "model")]
         paml: Option<std::path::PathBuf>,
+        #[structopt(long = "model")]
+        model: Option<String>,
+        #[structopt(default_value = "matrix", long = "frequencies")]
+        frequencies: amino_acids::FrequencyMode,
+        #[structopt(default_value = "uniform", long = "rates")]
+        rates: amino_acids::SiteRates,
         output: std::path::PathBuf,
         #[structopt(default_value = "1000", long = "rounds")]
         rounds: u32,
+        #[structopt(default_value = "1337", long = "seed")]
+        seed: u64,
+        #[structopt(flatten)]
+        schedule: SearchSchedule,
+        #[structopt(long = "resume")]
+        resume: bool,
+        #[structopt(long = "initial-tree")]
+        initial_tree: Option<std::path::PathBuf>,
+        #[structopt(long = "compress-graph")]
+        compress_graph: bool,
     },
     ExpandSearch {
         target: std::path::PathBuf,
@@ -61,6 +270,15 @@ enum CommandLine {
         rounds: u32,
         #[structopt(long = "seed")]
         seed: u64,
+        #[structopt(flatten)]
+        schedule: SearchSchedule,
+        #[structopt(long = "resume")]
+        resume: bool,
+    },
+    Models,
+    EstimateModel {
+        target: std::path::PathBuf,
+        output: std::path::PathBuf,
     },
     BenchMoves {
         target: std::path::PathBuf,
@@ -68,6 +286,75 @@ enum CommandLine {
     Reanalyze {
         target: std::path::PathBuf,
     },
+    Score {
+        target: std::path::PathBuf,
+        #[structopt(long = "alignment")]
+        alignment: Option<std::path::PathBuf>,
+        #[structopt(long = "paml", conflicts_with = "model")]
+        paml: Option<std::path::PathBuf>,
+        #[structopt(long = "model")]
+        model: Option<String>,
+        #[structopt(long = "parameter")]
+        parameter: Option<f64>,
+        #[structopt(long = "rates")]
+        rates: Option<amino_acids::SiteRates>,
+        #[structopt(long = "optimize-parameter")]
+        optimize_parameter: bool,
+    },
+    Replay {
+        source: std::path::PathBuf,
+        target: std::path::PathBuf,
+        #[structopt(long = "step")]
+        step: Option<usize>,
+        #[structopt(long = "probability", allow_hyphen_values = true)]
+        probability: Option<f64>,
+        #[structopt(long = "initial-tree")]
+        initial_tree: Option<std::path::PathBuf>,
+    },
+    Sample {
+        source: std::path::PathBuf,
+        target: std::path::PathBuf,
+        #[structopt(flatten)]
+        options: SampleOptions,
+    },
+    Bootstrap {
+        target: std::path::PathBuf,
+        #[structopt(default_value = "100", long = "replicates")]
+        replicates: usize,
+        // drop this fraction of the columns instead of resampling them
+        #[structopt(long = "jackknife")]
+        jackknife: Option<f64>,
+        #[structopt(default_value = "1337", long = "seed")]
+        seed: u64,
+    },
+    Compare {
+        lhs: std::path::PathBuf,
+        rhs: std::path::PathBuf,
+        // a directory to write compare.json and compare.html to
+        #[structopt(default_value = "compare", long = "output")]
+        output: std::path::PathBuf,
+    },
+    Consensus {
+        // run directories, graph.json files, or logs of graphs such as
+        // samples.log and bootstrap-replicates.log
+        #[structopt(required = true)]
+        inputs: Vec<std::path::PathBuf>,
+        #[structopt(default_value = "consensus", long = "output")]
+        output: std::path::PathBuf,
+        // the fraction of the inputs a cluster or edge must be in to be kept
+        #[structopt(default_value = "0.5", long = "threshold")]
+        threshold: f64,
+    },
+    Simulate {
+        output: std::path::PathBuf,
+        #[structopt(flatten)]
+        options: SimulateOptions,
+    },
+    Benchmark {
+        output: std::path::PathBuf,
+        #[structopt(flatten)]
+        options: BenchmarkOptions,
+    },
     DebugMove {
         source: std::path::PathBuf,
         the_move: optimization::moves::GraphMove,
@@ -88,210 +375,432 @@ enum CommandLine {
     }
 }
 
-fn analyze(path: &Path, rounds: u32) -> Result<()> {
-    let model = amino_acids::read_paml_matrix(std::fs::File::open(path.join("matrix.paml"))?)?;
-    let alignment =
-        alignment::read_alignment(std::fs::File::open(path.join("alignment.fasta"))?, &model)?;
-
-    let mut graph = graph::Graph::new(&model, &alignment);
-
-    if alignment.positions.is_empty() {
-        eprintln!("All sequences are identical");
-        serde_json::to_writer_pretty(
-            std::fs::File::create(path.join("graph.json"))?,
-            &graph.exported(),
-        )?;
+fn fit_frequencies(path: &Path, mode: amino_acids::FrequencyMode) -> Result<()> {
+    if mode == amino_acids::FrequencyMode::Matrix {
         return Ok(());
     }
 
-    let mut star = graph.clone();
-    for position in alignment.positions.ids() {
-        star.set_amino_acid(NodeId(0), position, AminoAcid::Gap);
+    let model = amino_acids::read_paml_matrix(compression::open(&path.join("matrix.paml"))?)?;
+    let alignment =
+        alignment::read_alignment(compression::open(&path.join("alignment.fasta"))?, &model)?;
+    let mut fitted = model.with_frequencies(&alignment.residue_counts());
+
+    if mode == amino_acids::FrequencyMode::Optimized {
+        let alignment =
+            alignment::read_alignment(compression::open(&path.join("alignment.fasta"))?, &fitted)?;
+        let mut graph = graph::Graph::new(&fitted, &alignment);
+        if !alignment.positions.is_empty() {
+            println!("Building tree to fit residue frequencies");
+            optimization::nn_join(&mut graph);
+            optimization::optimize_parameter(&mut graph);
+        }
+        fitted = model.with_frequencies(&optimization::insert_counts(&mut graph));
     }
 
+    let mut output = std::io::BufWriter::new(std::fs::File::create(path.join("matrix.paml"))?);
+    amino_acids::write_paml_matrix(&fitted, &mut output)?;
+    output.flush()?;
+    Ok(())
+}
 
-    println!("Building initial nearest neighbor tree");
-    optimization::nn_join(&mut graph);
-    optimization::optimize_parameter(&mut graph);
-    optimization::optimize_parameter(&mut star);
-    graph.validate();
+// the graph the search starts hill climbing from
+fn initial_graph<'a>(
+    model: &'a amino_acids::AminoAcidModel,
+    alignment: &'a Alignment,
+    rates: amino_acids::SiteRates,
+    initial_tree: Option<&Path>,
+) -> Result<Graph<'a>> {
+    let mut graph = graph::Graph::new(model, alignment);
+    graph.set_site_rates(rates);
+
+    if let Some(initial_tree) = initial_tree {
+        println!("Building initial tree from {}", initial_tree.display());
+        let tree = newick::parse_newick(&std::fs::read_to_string(initial_tree)?)?;
+        graph = graph::Graph::from_newick(model, alignment, &tree)?;
+        graph.set_site_rates(rates);
+        optimization::optimize_parameter(&mut graph);
+        graph.validate();
+    } else {
+        let mut star = graph.clone();
+        for position in alignment.positions.ids() {
+            star.set_amino_acid(NodeId(0), position, AminoAcid::Gap);
+        }
+
+        println!("Building initial nearest neighbor tree");
+        optimization::nn_join(&mut graph);
+        optimization::optimize_parameter(&mut graph);
+        optimization::optimize_parameter(&mut star);
+        graph.validate();
 
-    dbg!(star.parameter(), graph.parameter());
+        dbg!(star.parameter(), graph.parameter());
+        if !rates.is_uniform() {
+            println!("Site rates {}", graph.site_rates());
+        }
 
-    if star.probability() > graph.probability() {
-        println!("Swapping nearest neighbor tree for preferred star phylogeny");
-        std::mem::swap(&mut graph, &mut star);
+        if star.probability() > graph.probability() {
+            println!("Swapping nearest neighbor tree for preferred star phylogeny");
+            std::mem::swap(&mut graph, &mut star);
+        }
     }
+    Ok(graph)
+}
 
-    let mut moves = std::fs::File::create(path.join("moves.log"))?;
-    let mut rounds_log = std::fs::File::create(path.join("rounds.log"))?;
+fn analyze(
+    path: &Path,
+    rounds: u32,
+    seed: u64,
+    schedule: &SearchSchedule,
+    resume: bool,
+    initial_tree: Option<&Path>,
+    compress_graph: bool,
+) -> Result<()> {
+    let directory = WorkingDirectory::open(path)?;
+    let model = &directory.model;
+    let alignment = &directory.alignment;
+
+    let checkpoint = if resume {
+        Checkpoint::load(path, Stage::Infer)?
+    } else {
+        // a fresh search makes any earlier checkpoint stale
+        Checkpoint::remove(path, Stage::Infer)?;
+        Checkpoint::remove(path, Stage::ExpandSearch)?;
+        None
+    };
+
+    let bucket_bases = checkpoint
+        .as_ref()
+        .map(|checkpoint| checkpoint.bucket_bases(checkpoint.buckets.len()));
+    let (mut graph, mut buckets, mut random, mut moves, mut rounds_log, completed_rounds) =
+        if let Some(checkpoint) = checkpoint {
+            schedule.check_resumed(&checkpoint)?;
+            println!("Resuming after round {}", checkpoint.completed_rounds);
+            let (moves, rounds_log) = checkpoint.reopen_logs(path)?;
+            let (graph, buckets) = checkpoint.restore(model, alignment)?;
+            (
+                graph,
+                buckets,
+                checkpoint.random,
+                moves,
+                rounds_log,
+                checkpoint.completed_rounds,
+            )
+        } else {
+            let rates = amino_acids::read_site_rates(path)?;
+
+            if alignment.positions.is_empty() {
+                eprintln!("All sequences are identical");
+                let mut graph = graph::Graph::new(model, alignment);
+                graph.set_site_rates(rates);
+                WorkingDirectory::save(path, &graph, compress_graph)?;
+                return Ok(());
+            }
 
-    let mut random = rand::rngs::StdRng::seed_from_u64(1337);
+            let mut graph = initial_graph(model, alignment, rates, initial_tree)?;
 
-    loop {
-        graph.validate();
-        println!("Hill Climbing: {:?}", graph.probability());
+            let mut moves = std::fs::File::create(path.join(search_log::MOVES_LOG))?;
+            let rounds_log = std::fs::File::create(path.join(search_log::ROUNDS_LOG))?;
 
-        let mut new_graph = graph.clone();
-        let made_moves = optimization::optimize(&mut new_graph);
-        for m in made_moves {
-            writeln!(
-                &mut moves,
-                "{:?}\t{:?}\t{:?}",
-                m.the_move, m.probability, m.kind
-            )?;
-        }
+            let mut random = Random::seed_from_u64(seed);
 
-        if new_graph.probability() > graph.probability() {
-            graph = new_graph;
-        } else {
-            break;
-        }
-    }
+            let mut logged = 0;
+            loop {
+                graph.validate();
+                println!("Hill Climbing: {:?}", graph.probability());
 
-    let mut buckets = (0..8)
-        .map(|_| {
-            let mut new_graph = graph.clone();
-            let made_moves = optimization::moves::shuffle(&mut new_graph, &mut random, 7);
-            (new_graph, made_moves)
-        })
-        .collect_vec();
-    serde_json::to_writer_pretty(
-        std::fs::File::create(path.join("graph.json"))?,
-        &graph.exported(),
-    )?;
-    std::fs::write(path.join("parameter.txt"), format!("{}", graph.parameter().raw()))?;
+                let mut new_graph = graph.clone();
+                let made_moves = optimization::optimize(&mut new_graph);
+
+                if new_graph.probability() > graph.probability() {
+                    let base = logged;
+                    for m in &made_moves {
+                        search_log::write_record(
+                            &mut moves,
+                            &MoveRecord::new(m, None, None, base),
+                        )?;
+                        logged += 1;
+                    }
+                    graph = new_graph;
+                } else {
+                    break;
+                }
+            }
+
+            let buckets = (0..schedule.buckets)
+                .map(|_| {
+                    let mut new_graph = graph.clone();
+                    let made_moves = optimization::moves::shuffle(
+                        &mut new_graph,
+                        &mut random,
+                        schedule.shuffle_moves,
+                    );
+                    (new_graph, made_moves)
+                })
+                .collect_vec();
+            WorkingDirectory::save(path, &graph, compress_graph)?;
+
+            (graph, buckets, random, moves, rounds_log, 0)
+        };
+    let mut logged = search_log::count_records(&path.join(search_log::MOVES_LOG))?;
+    let mut bucket_bases = bucket_bases.unwrap_or_else(|| vec![logged; buckets.len()]);
 
     /*     while !optimization::moves::find_improvement(&mut graph, &mut random) {
 
     }*/
 
+    let bucket_count = buckets.len() as u32;
+    let mut stopping = StoppingRules::new(schedule, graph.probability(), completed_rounds);
     let mut progress = pbr::ProgressBar::new(u64::from(rounds));
-    for _ in 0..rounds / 8 {
-        serde_json::to_writer_pretty(
-            std::fs::File::create(path.join("graph.json"))?,
-            &graph.exported(),
-        )?;
-
-        std::fs::write(path.join("parameter.txt"), format!("{}", graph.parameter().raw()))?;
+    progress.set(u64::from(completed_rounds));
+    for round in completed_rounds..rounds / bucket_count * bucket_count {
+        let y = round % bucket_count;
+        if y == 0 {
+            WorkingDirectory::save(path, &graph, compress_graph)?;
+        }
 
-        for y in 0..8 {
-            writeln!(&mut rounds_log, "{:?}", graph.probability())?;
-            let buckets2: Vec<_> = buckets
-                .into_par_iter()
-                .map(|(mut graph, mut made_moves)| {
-                    let baseline = graph.probability();
-                    made_moves.extend(optimization::optimize(&mut graph));
-                    let probability = graph.probability();
+        search_log::write_record(&mut rounds_log, &RoundRecord::new(&mut graph, round))?;
+        let annealing = schedule.annealing(round, &mut random);
+        let buckets2: Vec<_> = buckets
+            .into_par_iter()
+            .zip(annealing)
+            .map(|((mut graph, mut made_moves), annealing)| {
+                let baseline = graph.probability();
+                // a bucket that anneals has changed if any move was accepted,
+                // even though its probability may have dropped
+                let mut annealed = false;
+                if let Some((temperature, seed)) = annealing {
+                    let annealing_moves = optimization::moves::anneal(
+                        &mut graph,
+                        &mut Random::seed_from_u64(seed),
+                        temperature,
+                        schedule.annealing_steps,
+                    );
+                    annealed = !annealing_moves.is_empty();
+                    made_moves.extend(annealing_moves);
+                }
+                made_moves.extend(optimization::optimize(&mut graph));
+                let probability = graph.probability();
 
-                    (graph, probability > baseline, made_moves)
-                })
-                .collect();
+                (graph, annealed || probability > baseline, made_moves)
+            })
+            .collect();
 
-            buckets = buckets2
-                .into_iter()
-                .enumerate()
-                .map(|(index, (mut new_graph, changed, mut made_moves))| {
-                    if new_graph.probability() > graph.probability() {
-                        graph = new_graph.clone();
-                        for the_move in made_moves.drain(..) {
-                            writeln!(
-                                &mut moves,
-                                "{:?}\t{:?}\t{:?}",
-                                the_move.the_move, the_move.probability, the_move.kind
-                            )
-                            .unwrap();
-                        }
-                    }
-                    if index == y || !changed {
-                        new_graph = graph.clone();
-                        made_moves = optimization::moves::shuffle(&mut new_graph, &mut random, 7);
+        buckets = buckets2
+            .into_iter()
+            .enumerate()
+            .map(|(index, (mut new_graph, changed, mut made_moves))| {
+                if new_graph.probability() > graph.probability() {
+                    graph = new_graph.clone();
+                    for the_move in made_moves.drain(..) {
+                        search_log::write_record(
+                            &mut moves,
+                            &MoveRecord::new(
+                                &the_move,
+                                Some(index),
+                                Some(round),
+                                bucket_bases[index],
+                            ),
+                        )
+                        .unwrap();
+                        logged += 1;
                     }
-                    (new_graph, made_moves)
-                })
-                .collect();
+                    bucket_bases[index] = logged;
+                }
+                if index == y as usize || !changed {
+                    new_graph = graph.clone();
+                    made_moves = optimization::moves::shuffle(
+                        &mut new_graph,
+                        &mut random,
+                        schedule.shuffle_moves,
+                    );
+                    bucket_bases[index] = logged;
+                }
+                (new_graph, made_moves)
+            })
+            .collect();
+
+        // stopping early checkpoints the round so that the search can be
+        // resumed with other limits
+        let stop = stopping.check(round, graph.probability());
+        if y == bucket_count - 1 || stop.is_some() {
+            Checkpoint::new(
+                round + 1,
+                &random,
+                &moves,
+                &rounds_log,
+                &graph,
+                &buckets,
+                &bucket_bases,
+            )?
+            .save(path, Stage::Infer, compress_graph)?;
+        }
 
-            progress.message(&format!("{}: {:?} ", graph.classify(), graph.probability(),));
-            progress.inc();
+        progress.message(&format!("{}: {:?} ", graph.classify(), graph.probability(),));
+        progress.inc();
+        if let Some(reason) = stop {
+            println!("\nStopping after round {}: {}", round + 1, reason);
+            break;
         }
     }
+    // expand-search starts from the saved graph, which must be the one the
+    // logged moves lead to
+    WorkingDirectory::save(path, &graph, compress_graph)?;
     Ok(())
 }
 
-fn expand(path: &Path, rounds: u32, seed: u64) -> Result<()> {
-    let model = amino_acids::read_paml_matrix(std::fs::File::open(path.join("matrix.paml"))?)?;
-    let alignment =
-        alignment::read_alignment(std::fs::File::open(path.join("alignment.fasta"))?, &model)?;
-    let parameter = r64(std::fs::read_to_string(path.join("parameter.txt"))?.parse()?);
-
-    let mut moves = std::fs::OpenOptions::new()
-        .append(true)
-        .open(path.join("moves.log"))?;
-    let mut rounds_log = std::fs::OpenOptions::new()
-        .append(true)
-        .open(path.join("rounds.log"))?;
+fn expand(
+    path: &Path,
+    rounds: u32,
+    seed: u64,
+    schedule: &SearchSchedule,
+    resume: bool,
+) -> Result<()> {
+    let compress_graph = compression::graph_compressed(path);
+    let directory = WorkingDirectory::open(path)?;
+    let model = &directory.model;
+    let alignment = &directory.alignment;
+
+    let checkpoint = if resume {
+        Checkpoint::load(path, Stage::ExpandSearch)?
+    } else {
+        Checkpoint::remove(path, Stage::ExpandSearch)?;
+        None
+    };
+
+    let bucket_bases = checkpoint
+        .as_ref()
+        .map(|checkpoint| checkpoint.bucket_bases(checkpoint.buckets.len()));
+    let (mut graph, mut buckets, mut random, mut moves, mut rounds_log, completed_rounds) =
+        if let Some(checkpoint) = checkpoint {
+            schedule.check_resumed(&checkpoint)?;
+            println!("Resuming after round {}", checkpoint.completed_rounds);
+            let (moves, rounds_log) = checkpoint.reopen_logs(path)?;
+            let (graph, buckets) = checkpoint.restore(model, alignment)?;
+            (
+                graph,
+                buckets,
+                checkpoint.random,
+                moves,
+                rounds_log,
+                checkpoint.completed_rounds,
+            )
+        } else {
+            let moves = std::fs::OpenOptions::new()
+                .append(true)
+                .open(path.join(search_log::MOVES_LOG))?;
+            let rounds_log = std::fs::OpenOptions::new()
+                .append(true)
+                .open(path.join(search_log::ROUNDS_LOG))?;
 
-    let mut random = rand::rngs::StdRng::seed_from_u64(seed);
+            let mut random = Random::seed_from_u64(seed);
 
-    let exported = serde_json::from_reader(std::fs::File::open(path.join("graph.json"))?)?;
+            let graph = directory.graph()?;
 
-    let mut graph = graph::Graph::from_exported(&model, &alignment, parameter, &exported)?;
+            let buckets = (0..schedule.buckets)
+                .map(|_| {
+                    let mut new_graph = graph.clone();
+                    let made_moves = optimization::moves::shuffle(
+                        &mut new_graph,
+                        &mut random,
+                        schedule.shuffle_moves,
+                    );
+                    (new_graph, made_moves)
+                })
+                .collect_vec();
+            compression::write_graph(path, &graph, compress_graph)?;
 
-    let mut buckets = (0..8)
-        .map(|_| {
-            let mut new_graph = graph.clone();
-            let made_moves = optimization::moves::shuffle(&mut new_graph, &mut random, 7);
-            (new_graph, made_moves)
-        })
-        .collect_vec();
-    serde_json::to_writer_pretty(
-        std::fs::File::create(path.join("graph.json"))?,
-        &graph.exported(),
-    )?;
+            (graph, buckets, random, moves, rounds_log, 0)
+        };
+    let mut logged = search_log::count_records(&path.join(search_log::MOVES_LOG))?;
+    let mut bucket_bases = bucket_bases.unwrap_or_else(|| vec![logged; buckets.len()]);
 
+    let bucket_count = buckets.len() as u32;
+    let mut stopping = StoppingRules::new(schedule, graph.probability(), completed_rounds);
     let mut progress = pbr::ProgressBar::new(u64::from(rounds));
-    for _ in 0..rounds / 8 {
-        for y in 0..8 {
-            writeln!(&mut rounds_log, "{:?}", graph.probability())?;
-            let buckets2: Vec<_> = buckets
-                .into_par_iter()
-                .map(|(mut graph, mut made_moves)| {
-                    let baseline = graph.probability();
-                    made_moves.extend(optimization::moves::optimize(&mut graph));
-                    let probability = graph.probability();
-
-                    (graph, probability > baseline, made_moves)
-                })
-                .collect();
+    progress.set(u64::from(completed_rounds));
+    for round in completed_rounds..rounds / bucket_count * bucket_count {
+        let y = round % bucket_count;
+        search_log::write_record(&mut rounds_log, &RoundRecord::new(&mut graph, round))?;
+        let annealing = schedule.annealing(round, &mut random);
+        let buckets2: Vec<_> = buckets
+            .into_par_iter()
+            .zip(annealing)
+            .map(|((mut graph, mut made_moves), annealing)| {
+                let baseline = graph.probability();
+                // a bucket that anneals has changed if any move was accepted,
+                // even though its probability may have dropped
+                let mut annealed = false;
+                if let Some((temperature, seed)) = annealing {
+                    let annealing_moves = optimization::moves::anneal(
+                        &mut graph,
+                        &mut Random::seed_from_u64(seed),
+                        temperature,
+                        schedule.annealing_steps,
+                    );
+                    annealed = !annealing_moves.is_empty();
+                    made_moves.extend(annealing_moves);
+                }
+                made_moves.extend(optimization::moves::optimize(&mut graph));
+                let probability = graph.probability();
 
-            buckets = buckets2
-                .into_iter()
-                .enumerate()
-                .map(|(index, (mut new_graph, changed, mut made_moves))| {
-                    if new_graph.probability() > graph.probability() {
-                        graph = new_graph.clone();
-                        for the_move in made_moves.drain(..) {
-                            writeln!(
-                                &mut moves,
-                                "{:?}\t{:?}\t{:?}",
-                                the_move.the_move, the_move.probability, the_move.kind
-                            )?;
-                        }
-                        serde_json::to_writer_pretty(
-                            std::fs::File::create(path.join("graph.json"))?,
-                            &graph.exported(),
+                (graph, annealed || probability > baseline, made_moves)
+            })
+            .collect();
+
+        buckets = buckets2
+            .into_iter()
+            .enumerate()
+            .map(|(index, (mut new_graph, changed, mut made_moves))| {
+                if new_graph.probability() > graph.probability() {
+                    graph = new_graph.clone();
+                    for the_move in made_moves.drain(..) {
+                        search_log::write_record(
+                            &mut moves,
+                            &MoveRecord::new(
+                                &the_move,
+                                Some(index),
+                                Some(round),
+                                bucket_bases[index],
+                            ),
                         )?;
+                        logged += 1;
                     }
-                    if index == y || !changed {
-                        new_graph = graph.clone();
-                        made_moves = optimization::moves::shuffle(&mut new_graph, &mut random, 7);
-                    }
-                    Ok((new_graph, made_moves))
-                })
-                .collect::<Result<Vec<_>>>()?;
+                    bucket_bases[index] = logged;
+                    compression::write_graph(path, &graph, compress_graph)?;
+                }
+                if index == y as usize || !changed {
+                    new_graph = graph.clone();
+                    made_moves = optimization::moves::shuffle(
+                        &mut new_graph,
+                        &mut random,
+                        schedule.shuffle_moves,
+                    );
+                    bucket_bases[index] = logged;
+                }
+                Ok((new_graph, made_moves))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        // stopping early checkpoints the round so that the search can be
+        // resumed with other limits
+        let stop = stopping.check(round, graph.probability());
+        if y == bucket_count - 1 || stop.is_some() {
+            Checkpoint::new(
+                round + 1,
+                &random,
+                &moves,
+                &rounds_log,
+                &graph,
+                &buckets,
+                &bucket_bases,
+            )?
+            .save(path, Stage::ExpandSearch, compress_graph)?;
+        }
 
-            progress.message(&format!("{:?} ", graph.probability(),));
-            progress.inc();
+        progress.message(&format!("{:?} ", graph.probability(),));
+        progress.inc();
+        if let Some(reason) = stop {
+            println!("\nStopping after round {}: {}", round + 1, reason);
+            break;
         }
     }
     /*
@@ -362,14 +871,8 @@ fn expand(path: &Path, rounds: u32, seed: u64) -> Result<()> {
 const REPORT_TEMPLATE: &[u8] = include_bytes!("./report-template.html");
 
 fn bench_moves(path: &Path) -> Result<()> {
-    let model = amino_acids::read_paml_matrix(std::fs::File::open(path.join("matrix.paml"))?)?;
-    let alignment =
-        alignment::read_alignment(std::fs::File::open(path.join("alignment.fasta"))?, &model)?;
-    let parameter = r64(std::fs::read_to_string(path.join("parameter.txt"))?.parse()?);
-
-    let exported = serde_json::from_reader(std::fs::File::open(path.join("graph.json"))?)?;
-
-    let mut graph = graph::Graph::from_exported(&model, &alignment, parameter, &exported)?;
+    let directory = WorkingDirectory::open(path)?;
+    let mut graph = directory.graph()?;
 
     graph.compact();
 
@@ -379,25 +882,729 @@ fn bench_moves(path: &Path) -> Result<()> {
     Ok(())
 }
 
-fn build_reports(path: &Path) -> Result<()> {
-    let model = amino_acids::read_paml_matrix(std::fs::File::open(path.join("matrix.paml"))?)?;
-    let alignment =
-        alignment::read_alignment(std::fs::File::open(path.join("alignment.fasta"))?, &model)?;
-    let parameter = r64(std::fs::read_to_string(path.join("parameter.txt"))?.parse()?);
+fn estimate_model(path: &Path, output: &Path) -> Result<()> {
+    let directory = WorkingDirectory::open(path)?;
+    let mut graph = directory.graph()?;
+
+    let estimated = optimization::estimate_model(&mut graph);
+
+    let transitions = graph.stats.transitions;
+    println!(
+        "Transitions: {:?} with the current matrix, {:?} with the estimated one",
+        graph.parameterized_model().likelihood(&transitions),
+        estimated
+            .parameterize(graph.parameter())
+            .likelihood(&transitions)
+    );
+
+    let mut write = std::io::BufWriter::new(std::fs::File::create(output)?);
+    amino_acids::write_paml_matrix(&estimated, &mut write)?;
+    write.flush()?;
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct Score {
+    parameter: R64,
+    rates: amino_acids::SiteRates,
+    components: graph::LikelihoodComponents,
+    #[serde(flatten)]
+    stats: graph::FullStats,
+}
+
+// target is either a directory written by infer or a graph.json file, and
+// anything not given on the command line is read from beside it
+fn score(
+    target: &Path,
+    alignment: Option<&Path>,
+    paml: Option<&Path>,
+    model: Option<&str>,
+    parameter: Option<f64>,
+    rates: Option<amino_acids::SiteRates>,
+    optimize_parameter: bool,
+) -> Result<()> {
+    let (path, exported) = if target.is_dir() {
+        (target, compression::read_graph(target)?)
+    } else {
+        (
+            target.parent().unwrap_or_else(|| Path::new(".")),
+            serde_json::from_reader(compression::open(target)?)?,
+        )
+    };
+
+    let model = match (paml, model) {
+        (Some(paml), _) => amino_acids::read_paml_matrix(compression::open(paml)?)?,
+        (None, Some(name)) => {
+            amino_acids::read_paml_matrix(amino_acids::builtin_model(name)?.paml)?
+        }
+        (None, None) => {
+            amino_acids::read_paml_matrix(compression::open(&path.join("matrix.paml"))?)?
+        }
+    };
+
+    let alignment = match alignment {
+        Some(alignment) => {
+            let format = formats::AlignmentFormat::from_path(alignment);
+            let mut records = formats::read_records(compression::open(alignment)?, format)?;
+            alignment::validate_records(&records, false)?;
+            alignment::normalize_records(&mut records, amino_acids::RareResiduePolicy::Map)?;
+            alignment::build_alignment(&records, &model)?
+        }
+        None => read_alignment(compression::open(&path.join("alignment.fasta"))?, &model)?,
+    };
+
+    let parameter = match parameter {
+        Some(parameter) => r64(parameter),
+        None if path.join("parameter.txt").exists() => {
+            r64(std::fs::read_to_string(path.join("parameter.txt"))?
+                .trim()
+                .parse()?)
+        }
+        None if optimize_parameter => r64(1.0),
+        None => {
+            bail!("No parameter.txt beside the graph, pass --parameter or --optimize-parameter")
+        }
+    };
+    let rates = match rates {
+        Some(rates) => rates,
+        None => amino_acids::read_site_rates(path)?,
+    };
+
+    let mut graph = Graph::from_exported(&model, &alignment, parameter, &exported)?;
+    graph.set_site_rates(rates);
+    if optimize_parameter {
+        optimization::optimize_parameter(&mut graph);
+    }
+
+    let score = Score {
+        parameter: graph.parameter(),
+        rates: graph.site_rates(),
+        components: graph.likelihood_components(),
+        stats: graph.full_stats(),
+    };
+    serde_json::to_writer_pretty(std::io::stdout(), &score)?;
+    println!();
+    Ok(())
+}
+
+fn replay(
+    source: &Path,
+    target: &Path,
+    step: Option<usize>,
+    probability: Option<f64>,
+    initial_tree: Option<&Path>,
+) -> Result<()> {
+    let directory = WorkingDirectory::open(source)?;
+    let records: Vec<MoveRecord> =
+        search_log::read_records(compression::open(&source.join(search_log::MOVES_LOG))?)?;
+    let start = initial_graph(
+        &directory.model,
+        &directory.alignment,
+        amino_acids::read_site_rates(source)?,
+        initial_tree,
+    )?;
+
+    let mut replayed = 0;
+    let mut mismatches = 0;
+    let graph = search_log::replay(start, &records, |index, record, graph| {
+        replayed = index;
+        let actual = graph.probability();
+        if (actual.log2() - record.probability.log2()).abs() > 1e-6 {
+            mismatches += 1;
+            println!(
+                "Record {}: {} logged {:?}, replayed {:?}",
+                index,
+                record.the_move.as_deref().unwrap_or("refit"),
+                record.probability,
+                actual
+            );
+        }
+        let reached = match probability {
+            Some(probability) => record.probability.log2() >= probability,
+            None => false,
+        };
+        step != Some(index) && !reached
+    })?;
+    println!(
+        "Replayed {} of {} records, {} differed from the log",
+        replayed,
+        records.len(),
+        mismatches
+    );
+
+    std::fs::create_dir_all(target)?;
+    compression::decompress(&source.join("matrix.paml"), &target.join("matrix.paml"))?;
+    compression::decompress(
+        &source.join("alignment.fasta"),
+        &target.join("alignment.fasta"),
+    )?;
+    WorkingDirectory::save(target, &graph, compression::graph_compressed(source))?;
+    build_reports(target)
+}
+
+fn sample(source: &Path, target: &Path, options: &SampleOptions) -> Result<()> {
+    if options.thin == 0 {
+        bail!("--thin must be at least 1");
+    }
+    if options.chains == 0 {
+        bail!("--chains must be at least 1");
+    }
+    if options.max_heat < 1.0 {
+        bail!("--max-heat must be at least 1");
+    }
+    if options.swap_interval == 0 {
+        bail!("--swap-interval must be at least 1");
+    }
+    let directory = WorkingDirectory::open(source)?;
+    std::fs::create_dir_all(target)?;
+    compression::decompress(&source.join("matrix.paml"), &target.join("matrix.paml"))?;
+    compression::decompress(
+        &source.join("alignment.fasta"),
+        &target.join("alignment.fasta"),
+    )?;
+
+    let graph = directory.graph()?;
+    let mut best = graph.clone();
+    let mut tempering = sampling::Tempering::new(
+        graph,
+        Random::seed_from_u64(options.seed),
+        &sampling::heat_ladder(options.chains, options.max_heat),
+    );
+    let mut samples =
+        std::io::BufWriter::new(std::fs::File::create(target.join(sampling::SAMPLES_LOG))?);
+    let mut support = sampling::Support::default();
+
+    let mut progress = pbr::ProgressBar::new(options.iterations);
+    let mut iteration = 0;
+    while iteration < options.iterations {
+        let steps = options.swap_interval.min(options.iterations - iteration);
+        // only the unheated chain is sampled
+        tempering.run(steps, |graph| {
+            iteration += 1;
+            if iteration > options.burn_in && (iteration - options.burn_in) % options.thin == 0 {
+                let sample = sampling::Sample {
+                    iteration,
+                    probability: graph.probability(),
+                    nodes: graph.exported(),
+                };
+                search_log::write_record(&mut samples, &sample)?;
+                support.add(&sample);
+                if sample.probability > best.probability() {
+                    best = graph.clone();
+                }
+            }
+            Ok(())
+        })?;
+        progress.message(&format!("{:?} ", tempering.cold().graph().probability()));
+        progress.set(iteration);
+    }
+    progress.finish();
+    samples.flush()?;
+
+    let cold = tempering.cold();
+    println!("Accepted {} of {} proposals", cold.accepted, cold.proposed);
+    for (index, (accepted, proposed)) in tempering
+        .swaps_accepted
+        .iter()
+        .zip(&tempering.swaps_proposed)
+        .enumerate()
+    {
+        println!(
+            "Swapped chains {} and {} in {} of {} proposals",
+            index,
+            index + 1,
+            accepted,
+            proposed
+        );
+    }
+    compression::write_json(&target.join("support.json"), &support.report(), false)?;
+    // the directory holds the most probable sample so that it can be
+    // reported and scored
+    WorkingDirectory::save(target, &best, compression::graph_compressed(source))?;
+    build_reports(target)
+}
+
+// each replicate is climbed from a nearest neighbor tree to its local optimum,
+// as infer does before its bucket rounds
+fn bootstrap(
+    path: &Path,
+    replicates: usize,
+    resampling: bootstrap::Resampling,
+    seed: u64,
+) -> Result<()> {
+    let directory = WorkingDirectory::open(path)?;
+    let records = formats::read_records(
+        compression::open(&path.join("alignment.fasta"))?,
+        formats::AlignmentFormat::Fasta,
+    )?;
+    let rates = amino_acids::read_site_rates(path)?;
+    let column_count = records.first().map_or(0, |record| record.sequence.len());
+
+    let mut random = Random::seed_from_u64(seed);
+    let replicate_columns = (0..replicates)
+        .map(|_| resampling.columns(column_count, &mut random))
+        .collect_vec();
+
+    println!("Inferring {} replicates", replicates);
+    let graphs = replicate_columns
+        .into_par_iter()
+        .map(|columns| {
+            let alignment = alignment::build_alignment(
+                &bootstrap::resample(&records, &columns),
+                &directory.model,
+            )?;
+            let mut graph = graph::Graph::new(&directory.model, &alignment);
+            graph.set_site_rates(rates);
+            if !alignment.positions.is_empty() {
+                optimization::nn_join(&mut graph);
+                optimization::optimize_parameter(&mut graph);
+                loop {
+                    let mut new_graph = graph.clone();
+                    optimization::optimize(&mut new_graph);
+                    if new_graph.probability() > graph.probability() {
+                        graph = new_graph;
+                    } else {
+                        break;
+                    }
+                }
+            }
+            Ok(graph.exported())
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut output =
+        std::io::BufWriter::new(std::fs::File::create(path.join(bootstrap::REPLICATES_LOG))?);
+    for graph in &graphs {
+        search_log::write_record(&mut output, graph)?;
+    }
+    output.flush()?;
+
+    compression::write_json(
+        &path.join(bootstrap::BOOTSTRAP_FILE),
+        &bootstrap::Bootstrap {
+            resampling,
+            frequencies: clusters::Frequencies::new(&graphs),
+        },
+        false,
+    )?;
+    build_reports(path)
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn compare_graphs(lhs: &Path, rhs: &Path, output: &Path) -> Result<()> {
+    let comparison = compare::compare(
+        &compression::read_graph(lhs)?,
+        &compression::read_graph(rhs)?,
+    )?;
+    println!(
+        "{} shared clusters, {} only in {}, {} only in {}",
+        comparison.shared_clusters.len(),
+        comparison.lhs_clusters.len(),
+        lhs.display(),
+        comparison.rhs_clusters.len(),
+        rhs.display()
+    );
+    println!(
+        "{} shared edges, {} only in {}, {} only in {}",
+        comparison.shared_edges,
+        comparison.lhs_edges.len(),
+        lhs.display(),
+        comparison.rhs_edges.len(),
+        rhs.display()
+    );
+    println!(
+        "{} differing ancestral residues, distance {:.4}",
+        comparison.residues.len(),
+        comparison.distance
+    );
+
+    std::fs::create_dir_all(output)?;
+    compression::write_json(&output.join("compare.json"), &comparison, false)?;
+
+    let cluster =
+        |cluster: &clusters::Cluster| escape_html(&format!("{{{}}}", cluster.iter().join(", ")));
+    let lhs_name = escape_html(&lhs.display().to_string());
+    let rhs_name = escape_html(&rhs.display().to_string());
+    let mut page = std::io::BufWriter::new(std::fs::File::create(output.join("compare.html"))?);
+    writeln!(page, "<!DOCTYPE html>")?;
+    writeln!(page, "<html><head><meta charset=\"utf-8\">")?;
+    writeln!(page, "<title>{} vs {}</title>", lhs_name, rhs_name)?;
+    writeln!(
+        page,
+        "<style>body {{ font-family: sans-serif; }} td, th {{ padding: 2px 8px; text-align: left; vertical-align: top; }} .lhs {{ background: #ffe0e0; }} .rhs {{ background: #e0e0ff; }}</style>"
+    )?;
+    writeln!(page, "</head><body>")?;
+    writeln!(
+        page,
+        "<h1><span class=\"lhs\">{}</span> vs <span class=\"rhs\">{}</span></h1>",
+        lhs_name, rhs_name
+    )?;
+    writeln!(page, "<p>Distance {:.4}</p>", comparison.distance)?;
+    writeln!(page, "<table>")?;
+    writeln!(page, "<tr><th></th><th>Shared</th><th class=\"lhs\">Only in {}</th><th class=\"rhs\">Only in {}</th></tr>", lhs_name, rhs_name)?;
+    writeln!(
+        page,
+        "<tr><th>Clusters</th><td>{}</td><td>{}</td><td>{}</td></tr>",
+        comparison.shared_clusters.len(),
+        comparison.lhs_clusters.len(),
+        comparison.rhs_clusters.len()
+    )?;
+    writeln!(
+        page,
+        "<tr><th>Edges</th><td>{}</td><td>{}</td><td>{}</td></tr>",
+        comparison.shared_edges,
+        comparison.lhs_edges.len(),
+        comparison.rhs_edges.len()
+    )?;
+    writeln!(page, "</table>")?;
+
+    writeln!(page, "<h2>Clusters in one graph</h2>")?;
+    writeln!(page, "<ul>")?;
+    for (class, clusters) in [
+        ("lhs", &comparison.lhs_clusters),
+        ("rhs", &comparison.rhs_clusters),
+    ] {
+        for leaves in clusters {
+            writeln!(page, "<li class=\"{}\">{}</li>", class, cluster(leaves))?;
+        }
+    }
+    writeln!(page, "</ul>")?;
+
+    writeln!(page, "<h2>Edges in one graph</h2>")?;
+    writeln!(page, "<ul>")?;
+    for (class, edges) in [
+        ("lhs", &comparison.lhs_edges),
+        ("rhs", &comparison.rhs_edges),
+    ] {
+        for edge in edges {
+            writeln!(
+                page,
+                "<li class=\"{}\">{} &rarr; {}</li>",
+                class,
+                cluster(&edge.parent),
+                cluster(&edge.child)
+            )?;
+        }
+    }
+    writeln!(page, "</ul>")?;
+
+    writeln!(page, "<h2>Ancestral residues</h2>")?;
+    writeln!(page, "<table>")?;
+    writeln!(
+        page,
+        "<tr><th>Column</th><th>Cluster</th><th class=\"lhs\">{}</th><th class=\"rhs\">{}</th></tr>",
+        lhs_name, rhs_name
+    )?;
+    for difference in &comparison.residues {
+        writeln!(
+            page,
+            "<tr><td>{}</td><td>{}</td><td class=\"lhs\">{} ({})</td><td class=\"rhs\">{} ({})</td></tr>",
+            difference.column + 1,
+            cluster(&difference.cluster),
+            difference.lhs,
+            escape_html(&difference.lhs_node),
+            difference.rhs,
+            escape_html(&difference.rhs_node)
+        )?;
+    }
+    writeln!(page, "</table>")?;
+    writeln!(page, "</body></html>")?;
+    page.flush()?;
+    Ok(())
+}
 
-    let exported = serde_json::from_reader(std::fs::File::open(path.join("graph.json"))?)?;
+// a directory or graph.json holds one graph, a log one per line
+fn read_input_graphs(path: &Path) -> Result<Vec<clusters::ExportedNodes>> {
+    if path.is_dir() {
+        Ok(vec![compression::read_graph(path)?])
+    } else if path.ends_with(sampling::SAMPLES_LOG) {
+        Ok(
+            search_log::read_records::<sampling::Sample>(compression::open(path)?)?
+                .into_iter()
+                .map(|sample| sample.nodes)
+                .collect(),
+        )
+    } else if path.extension() == Some("log".as_ref()) {
+        search_log::read_records(compression::open(path)?)
+    } else {
+        Ok(vec![serde_json::from_reader(compression::open(path)?)?])
+    }
+}
+
+fn consensus(inputs: &[std::path::PathBuf], output: &Path, threshold: f64) -> Result<()> {
+    if threshold <= 0.0 || threshold > 1.0 {
+        bail!("--threshold must be more than 0 and at most 1");
+    }
+    // the alignment, model and rates come from beside the first input
+    let source = if inputs[0].is_dir() {
+        inputs[0].as_path()
+    } else {
+        inputs[0].parent().unwrap_or_else(|| Path::new("."))
+    };
+    let directory = WorkingDirectory::open(source)?;
+
+    let mut graphs = Vec::new();
+    for input in inputs {
+        graphs.extend(read_input_graphs(input)?);
+    }
+    println!("Consensus of {} graphs", graphs.len());
+    let frequencies = clusters::Frequencies::new(&graphs);
+
+    let mut graph = Graph::from_consensus(
+        &directory.model,
+        &directory.alignment,
+        &frequencies,
+        threshold,
+    )?;
+    graph.set_site_rates(amino_acids::read_site_rates(source)?);
+    optimization::optimize_parameter(&mut graph);
+    graph.validate();
+    println!("Consensus probability {:?}", graph.probability());
 
-    let mut graph = graph::Graph::from_exported(&model, &alignment, parameter, &exported)?;
+    std::fs::create_dir_all(output)?;
+    compression::decompress(&source.join("matrix.paml"), &output.join("matrix.paml"))?;
+    compression::decompress(
+        &source.join("alignment.fasta"),
+        &output.join("alignment.fasta"),
+    )?;
+    WorkingDirectory::save(output, &graph, compression::graph_compressed(source))?;
+    compression::write_json(
+        &output.join(consensus::CONSENSUS_FILE),
+        &consensus::Consensus {
+            threshold,
+            frequencies,
+        },
+        false,
+    )?;
+    build_reports(output)
+}
 
-    dbg!(graph.probability());
-    dbg!(graph.prior());
-    dbg!(graph.likelihood());
+// the output is a working directory holding the true graph, so that it can be
+// scored, reported and compared with what a search of its alignment finds
+fn simulate(output: &Path, options: &SimulateOptions) -> Result<()> {
+    if options.length == 0 {
+        bail!("--length must be at least 1");
+    }
+    if options.parameter < 0.0 {
+        bail!("--parameter must not be negative");
+    }
+    for (name, rate) in [
+        ("--insert-rate", options.insert_rate),
+        ("--delete-rate", options.delete_rate),
+    ] {
+        if !(0.0..=1.0).contains(&rate) {
+            bail!("{} must be between 0 and 1", name);
+        }
+    }
+
+    std::fs::create_dir_all(output)?;
+    if let Some(paml) = &options.paml {
+        compression::decompress(paml, &output.join("matrix.paml"))?;
+    } else {
+        let model = amino_acids::builtin_model(
+            options
+                .model
+                .as_deref()
+                .unwrap_or(amino_acids::DEFAULT_MODEL),
+        )?;
+        std::fs::write(output.join("matrix.paml"), model.paml)?;
+    }
+    let model = amino_acids::read_paml_matrix(compression::open(&output.join("matrix.paml"))?)?;
+
+    let mut random = Random::seed_from_u64(options.seed);
+    let topology = match &options.graph {
+        Some(graph) if graph.is_dir() => compression::read_graph(graph)?,
+        Some(graph) => serde_json::from_reader(compression::open(graph)?)?,
+        None => simulate::random_graph(options.sequences, options.extra_edges, &mut random)?,
+    };
+    let nodes = simulate::evolve(
+        &model,
+        &topology,
+        &simulate::Evolution {
+            parameter: r64(options.parameter),
+            rates: options.rates,
+            insert_rate: options.insert_rate,
+            delete_rate: options.delete_rate,
+            length: options.length,
+        },
+        &mut random,
+    )?;
+
+    let records = nodes
+        .values()
+        .filter_map(|node| {
+            Some(formats::Record {
+                id: node.sequence_id.clone()?,
+                line: 0,
+                sequence: node.amino_acids.as_bytes().to_vec(),
+            })
+        })
+        .collect_vec();
+    println!(
+        "Simulated {} sequences of {} columns",
+        records.len(),
+        records[0].sequence.len()
+    );
+    formats::write_fasta(
+        &records,
+        std::io::BufWriter::new(std::fs::File::create(output.join("alignment.fasta"))?),
+    )?;
+    compression::write_json(&output.join("graph.json"), &nodes, false)?;
+    std::fs::write(
+        output.join("parameter.txt"),
+        format!("{}", options.parameter),
+    )?;
+    amino_acids::write_site_rates(output, &options.rates)?;
+    build_reports(output)
+}
+
+// each dataset gets a directory holding the true graph in truth/ and what the
+// search found from its alignment in inferred/
+fn run_benchmark(output: &Path, options: &BenchmarkOptions) -> Result<()> {
+    options.schedule.validate()?;
+    if options.replicates == 0 {
+        bail!("--replicates must be at least 1");
+    }
+    let bits = |path: &Path| -> Result<f64> {
+        Ok(-WorkingDirectory::open(path)?
+            .graph()?
+            .probability()
+            .log2()
+            .raw())
+    };
+
+    let mut records = Vec::new();
+    for &sequences in &options.sequences {
+        for &parameter in &options.parameters {
+            for &extra_edges in &options.extra_edges {
+                for replicate in 0..options.replicates {
+                    let dataset = format!(
+                        "n{}-p{}-e{}-r{}",
+                        sequences, parameter, extra_edges, replicate
+                    );
+                    println!("Benchmarking {}", dataset);
+                    let seed = options.seed + records.len() as u64;
+                    let truth = output.join(&dataset).join("truth");
+                    let inferred = output.join(&dataset).join("inferred");
+
+                    simulate(
+                        &truth,
+                        &SimulateOptions {
+                            graph: None,
+                            sequences,
+                            extra_edges,
+                            length: options.length,
+                            paml: None,
+                            model: None,
+                            parameter,
+                            rates: amino_acids::SiteRates::default(),
+                            insert_rate: 0.01,
+                            delete_rate: 0.01,
+                            seed,
+                        },
+                    )?;
+                    std::fs::create_dir_all(&inferred)?;
+                    std::fs::copy(truth.join("matrix.paml"), inferred.join("matrix.paml"))?;
+                    std::fs::copy(
+                        truth.join("alignment.fasta"),
+                        inferred.join("alignment.fasta"),
+                    )?;
+                    amino_acids::write_site_rates(&inferred, &amino_acids::SiteRates::default())?;
+
+                    let started = std::time::Instant::now();
+                    analyze(
+                        &inferred,
+                        options.rounds,
+                        seed,
+                        &options.schedule,
+                        false,
+                        None,
+                        false,
+                    )?;
+                    let seconds = started.elapsed().as_secs_f64();
+                    build_reports(&inferred)?;
+
+                    let true_nodes = compression::read_graph(&truth)?;
+                    let record = benchmark::BenchmarkRecord {
+                        sequences,
+                        columns: true_nodes
+                            .values()
+                            .map(|node| node.amino_acids.chars().count())
+                            .next()
+                            .unwrap_or(0),
+                        parameter,
+                        extra_edges,
+                        replicate,
+                        seconds,
+                        true_bits: bits(&truth)?,
+                        inferred_bits: bits(&inferred)?,
+                        recovery: benchmark::Recovery::new(
+                            &true_nodes,
+                            &compression::read_graph(&inferred)?,
+                        )?,
+                        dataset,
+                    };
+                    println!(
+                        "{}: {:.2} bits against {:.2} for the truth, distance {:.3}, {:.1}s",
+                        record.dataset,
+                        record.inferred_bits,
+                        record.true_bits,
+                        record.recovery.distance,
+                        record.seconds
+                    );
+                    records.push(record);
+                }
+            }
+        }
+    }
+
+    compression::write_json(&output.join(benchmark::BENCHMARK_JSON), &records, false)?;
+    let mut csv = std::io::BufWriter::new(std::fs::File::create(
+        output.join(benchmark::BENCHMARK_CSV),
+    )?);
+    benchmark::write_csv(&records, &mut csv)?;
+    csv.flush()?;
+    Ok(())
+}
+
+fn build_reports(path: &Path) -> Result<()> {
+    let directory = WorkingDirectory::open(path)?;
+    let mut graph = directory.graph()?;
+    // the reports read inherited residues, which are only derived on scoring
+    graph.ensure_clean();
+
+    // bootstrap supports, or for a consensus the frequencies across its inputs
+    let support = match [bootstrap::BOOTSTRAP_FILE, consensus::CONSENSUS_FILE]
+        .iter()
+        .map(|file| path.join(file))
+        .find(|file| compression::exists(file))
+    {
+        Some(file) => {
+            let frequencies: clusters::Frequencies =
+                serde_json::from_reader(compression::open(&file)?)?;
+            Some(frequencies.for_nodes(&graph.exported()))
+        }
+        None => None,
+    };
+    let node_support = |node_id: NodeId| {
+        support
+            .as_ref()
+            .map(|support| &support[&format!("N{}", node_id.0)])
+    };
+    let edge_support = |child: NodeId, parent: NodeId| {
+        node_support(child).map(|support| support.parents[&format!("N{}", parent.0)])
+    };
 
     let mut output = std::fs::File::create(path.join("output.dot"))?;
     writeln!(output, "digraph {{")?;
     for node_id in graph.node_ids() {
         let label = match graph[node_id].kind {
-            NodeKind::Leaf(leaf) => alignment.sequence_ids[leaf].to_string(),
+            NodeKind::Leaf(leaf) => directory.alignment.sequence_ids[leaf].to_string(),
             NodeKind::Root => "Root".to_string(),
             NodeKind::Other => format!("N{}", node_id.0),
         };
@@ -419,13 +1626,38 @@ fn build_reports(path: &Path) -> Result<()> {
                 }
             }
         }
+        if let Some(frequency) = node_support(node_id).and_then(|support| support.cluster) {
+            writeln!(output, "<i>support {:.0}%</i><br/>", frequency * 100.0)?;
+        }
         writeln!(output, ">]")?;
         for &child in &graph[node_id].children {
-            writeln!(output, "N{} -> N{}", child.0, node_id.0)?;
+            match edge_support(child, node_id) {
+                Some(frequency) => writeln!(
+                    output,
+                    "N{} -> N{} [label=\"{:.0}\"]",
+                    child.0,
+                    node_id.0,
+                    frequency * 100.0
+                )?,
+                None => writeln!(output, "N{} -> N{}", child.0, node_id.0)?,
+            }
         }
     }
     writeln!(output, "}}")?;
 
+    std::fs::write(
+        path.join("output.nwk"),
+        graph.newick_annotated(|child, parent| {
+            let edge = edge_support(child, parent)?;
+            Some(
+                match node_support(child).and_then(|support| support.cluster) {
+                    Some(cluster) => format!("&support={:.2},edge={:.2}", cluster, edge),
+                    None => format!("&edge={:.2}", edge),
+                },
+            )
+        }),
+    )?;
+
     let mut output = std::fs::File::create(path.join("stats.json"))?;
     serde_json::to_writer_pretty(&mut output, &graph.full_stats())?;
 
@@ -434,6 +1666,11 @@ fn build_reports(path: &Path) -> Result<()> {
         writeln!(&mut output, "{:?} {:?}", node_id, graph[node_id].stats)?;
     }
 
+    // nodes carry their support, when there is any, for the report to show
+    let mut exported = serde_json::to_value(&compression::read_graph(path)?)?;
+    for (name, node_support) in support.iter().flatten() {
+        exported[name]["support"] = serde_json::to_value(node_support)?;
+    }
     let regex = regex::bytes::Regex::new("import ([A-Za-z0-9_]+) from\"../report.json\"").unwrap();
 
     let mut output = std::io::BufWriter::new(std::fs::File::create(path.join("report.html"))?);
@@ -538,48 +1775,6 @@ fn build_reports(path: &Path) -> Result<()> {
     Ok(())
 }
 
-fn fix_index(index: usize, alignment: &Alignment) -> PositionIndex {
-    let mut mapping = Vec::new();
-    let mut current_index = 0;
-    for position in alignment.raw_positions.values() {
-        if position.is_standard() {
-            mapping.push(current_index);
-            current_index += 1;
-        } else {
-            mapping.push(0);
-        }
-    }
-    return PositionIndex(mapping[index]);
-}
-
-fn fix_indexes(the_move: &mut GraphMove, alignment: &Alignment) {
-    let mut mapping = Vec::new();
-    let mut current_index = 0;
-    for position in alignment.raw_positions.values() {
-        if position.is_standard() {
-            mapping.push(current_index);
-            current_index += 1;
-        } else {
-            mapping.push(0);
-        }
-    }
-
-    match the_move {
-        GraphMove::Refactor(_, _) => {}
-        GraphMove::Remove(_) => {}
-        GraphMove::AddEdge(_, _) => {}
-        GraphMove::RemoveEdge(_, _) => {}
-        GraphMove::ChangeEdge(_, _, _) => {}
-        GraphMove::Reparent(_, _) => {}
-        GraphMove::SetAminoAcid(_, index, _) => {
-            *index = PositionIndex(mapping[index.0 as usize]);
-        }
-        GraphMove::FloodFill(_, index, _) => {
-            *index = PositionIndex(mapping[index.0 as usize]);
-        }
-    }
-}
-
 fn main() -> Result<()> {
     cli_init()?;
 
@@ -591,98 +1786,190 @@ fn main() -> Result<()> {
     match args {
         CommandLine::Infer {
             alignment,
+            format,
+            rare_residues,
             output,
             paml,
+            model,
+            frequencies,
+            rates,
             rounds,
+            seed,
+            schedule,
+            resume,
+            initial_tree,
+            compress_graph,
         } => {
+            schedule.validate()?;
             std::fs::create_dir_all(&output)?;
 
-            if let Some(paml) = paml {
-                std::fs::copy(paml, output.join("matrix.paml"))?;
-            } else {
-                std::fs::write(
-                    output.join("matrix.paml"),
-                    include_bytes!("./BLOSUM62.paml"),
+            if !(resume && Checkpoint::exists(&output, Stage::Infer)) {
+                if let Some(paml) = paml {
+                    compression::decompress(&paml, &output.join("matrix.paml"))?;
+                } else {
+                    let model = amino_acids::builtin_model(
+                        model.as_deref().unwrap_or(amino_acids::DEFAULT_MODEL),
+                    )?;
+                    std::fs::write(output.join("matrix.paml"), model.paml)?;
+                }
+                let format =
+                    format.unwrap_or_else(|| formats::AlignmentFormat::from_path(&alignment));
+                let mut records = formats::read_records(compression::open(&alignment)?, format)?;
+                alignment::validate_records(&records, false)?;
+                alignment::normalize_records(&mut records, rare_residues)?;
+                formats::write_fasta(
+                    &records,
+                    std::io::BufWriter::new(std::fs::File::create(output.join("alignment.fasta"))?),
                 )?;
+                fit_frequencies(&output, frequencies)?;
+                amino_acids::write_site_rates(&output, &rates)?;
             }
-            std::fs::copy(&alignment, output.join("alignment.fasta"))?;
 
-            analyze(&output, rounds)?;
+            analyze(
+                &output,
+                rounds,
+                seed,
+                &schedule,
+                resume,
+                initial_tree.as_deref(),
+                compress_graph,
+            )?;
             build_reports(&output)?;
         }
         CommandLine::ExpandSearch {
             target,
             rounds,
             seed,
+            schedule,
+            resume,
         } => {
-            expand(&target, rounds, seed)?;
+            schedule.validate()?;
+            expand(&target, rounds, seed, &schedule, resume)?;
             build_reports(&target)?;
         }
+        CommandLine::Models => {
+            for model in amino_acids::BUILTIN_MODELS {
+                let default = if model.name == amino_acids::DEFAULT_MODEL {
+                    " (default)"
+                } else {
+                    ""
+                };
+                println!("{}\t{}{}", model.name, model.description, default);
+            }
+        }
+        CommandLine::EstimateModel { target, output } => {
+            estimate_model(&target, &output)?;
+        }
         CommandLine::BenchMoves { target } => {
             bench_moves(&target)?;
         }
         CommandLine::Reanalyze { target } => {
             build_reports(&target)?;
         }
+        CommandLine::Score {
+            target,
+            alignment,
+            paml,
+            model,
+            parameter,
+            rates,
+            optimize_parameter,
+        } => {
+            score(
+                &target,
+                alignment.as_deref(),
+                paml.as_deref(),
+                model.as_deref(),
+                parameter,
+                rates,
+                optimize_parameter,
+            )?;
+        }
+        CommandLine::Replay {
+            source,
+            target,
+            step,
+            probability,
+            initial_tree,
+        } => {
+            replay(&source, &target, step, probability, initial_tree.as_deref())?;
+        }
+        CommandLine::Sample {
+            source,
+            target,
+            options,
+        } => {
+            sample(&source, &target, &options)?;
+        }
+        CommandLine::Bootstrap {
+            target,
+            replicates,
+            jackknife,
+            seed,
+        } => {
+            let resampling = match jackknife {
+                Some(fraction) if !(0.0..1.0).contains(&fraction) => {
+                    bail!("--jackknife must be at least 0 and less than 1")
+                }
+                Some(fraction) => bootstrap::Resampling::Jackknife(fraction),
+                None => bootstrap::Resampling::Bootstrap,
+            };
+            bootstrap(&target, replicates, resampling, seed)?;
+        }
+        CommandLine::Compare { lhs, rhs, output } => {
+            compare_graphs(&lhs, &rhs, &output)?;
+        }
+        CommandLine::Consensus {
+            inputs,
+            output,
+            threshold,
+        } => {
+            consensus(&inputs, &output, threshold)?;
+        }
+        CommandLine::Simulate { output, options } => {
+            simulate(&output, &options)?;
+        }
+        CommandLine::Benchmark { output, options } => {
+            run_benchmark(&output, &options)?;
+        }
         CommandLine::DebugMove {
             source,
-            mut the_move,
+            the_move,
             prefix,
         } => {
-            let model =
-                amino_acids::read_paml_matrix(std::fs::File::open(source.join("matrix.paml"))?)?;
-            let alignment = alignment::read_alignment(
-                std::fs::File::open(source.join("alignment.fasta"))?,
-                &model,
-            )?;
-            let parameter = r64(std::fs::read_to_string(source.join("parameter.txt"))?.parse()?);
+            let directory = WorkingDirectory::open(&source)?;
 
-            fix_indexes(&mut the_move, &alignment);
+            let the_move = the_move.with_alignment_columns(&directory.alignment);
 
-            let exported =
-                serde_json::from_reader(std::fs::File::open(source.join("graph.json"))?)?;
-
-            let mut graph = graph::Graph::from_exported(&model, &alignment, parameter, &exported)?;
+            let mut graph = directory.graph()?;
 
             let mut tracer = trace::BasicTracer::new(&prefix);
             optimization::moves::debug_move(&mut graph, the_move, &mut tracer);
         }
         CommandLine::ApplyMove {
             source,
-            mut the_move,
+            the_move,
             target,
         } => {
             std::fs::create_dir_all(&target)?;
-            std::fs::copy(&source.join("matrix.paml"), target.join("matrix.paml"))?;
-            std::fs::copy(&source.join("parameter.txt"), target.join("parameter.txt"))?;
-            std::fs::copy(
+            compression::decompress(&source.join("matrix.paml"), &target.join("matrix.paml"))?;
+            compression::decompress(
                 &source.join("alignment.fasta"),
-                target.join("alignment.fasta"),
-            )?;
-
-            let model =
-                amino_acids::read_paml_matrix(std::fs::File::open(source.join("matrix.paml"))?)?;
-            let alignment = alignment::read_alignment(
-                std::fs::File::open(source.join("alignment.fasta"))?,
-                &model,
+                &target.join("alignment.fasta"),
             )?;
-            let parameter = r64(std::fs::read_to_string(source.join("parameter.txt"))?.parse()?);
 
-            let exported =
-                serde_json::from_reader(std::fs::File::open(source.join("graph.json"))?)?;
+            let directory = WorkingDirectory::open(&source)?;
 
-            let mut graph = graph::Graph::from_exported(&model, &alignment, parameter, &exported)?;
+            let mut graph = directory.graph()?;
 
-            for m in &mut the_move {
-                fix_indexes(m, &alignment);
-            }
+            let the_move = the_move
+                .into_iter()
+                .map(|m| m.with_alignment_columns(&directory.alignment))
+                .collect();
 
             optimization::moves::apply_mutation(&mut graph, the_move);
 
-            serde_json::to_writer_pretty(
-                std::fs::File::create(target.join("graph.json"))?,
-                &graph.exported(),
-            )?;
+            WorkingDirectory::save(&target, &graph, compression::graph_compressed(&source))?;
 
             build_reports(&target)?;
         }
@@ -694,36 +1981,23 @@ fn main() -> Result<()> {
             target
         } => {
             std::fs::create_dir_all(&target)?;
-            std::fs::copy(&source.join("matrix.paml"), target.join("matrix.paml"))?;
-            std::fs::copy(&source.join("parameter.txt"), target.join("parameter.txt"))?;
-            std::fs::copy(
+            compression::decompress(&source.join("matrix.paml"), &target.join("matrix.paml"))?;
+            compression::decompress(
                 &source.join("alignment.fasta"),
-                target.join("alignment.fasta"),
+                &target.join("alignment.fasta"),
             )?;
 
-            let model =
-                amino_acids::read_paml_matrix(std::fs::File::open(source.join("matrix.paml"))?)?;
-            let alignment = alignment::read_alignment(
-                std::fs::File::open(source.join("alignment.fasta"))?,
-                &model,
-            )?;
-            let parameter = r64(std::fs::read_to_string(source.join("parameter.txt"))?.parse()?);
+            let directory = WorkingDirectory::open(&source)?;
 
-            let exported =
-                serde_json::from_reader(std::fs::File::open(source.join("graph.json"))?)?;
+            let mut graph = directory.graph()?;
 
-            let mut graph = graph::Graph::from_exported(&model, &alignment, parameter, &exported)?;
-
-            let index = fix_index(index, &alignment);
+            let index = directory.alignment.position_index(index);
 
 
 
             optimization::groups::apply_group(&mut graph, index, original, replacement);
 
-            serde_json::to_writer_pretty(
-                std::fs::File::create(target.join("graph.json"))?,
-                &graph.exported(),
-            )?;
+            WorkingDirectory::save(&target, &graph, compression::graph_compressed(&source))?;
 
             build_reports(&target)?;
         }
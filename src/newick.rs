@@ -0,0 +1,488 @@
+use crate::amino_acids::AminoAcidModel;
+use crate::optimization::analyze_amino_acids;
+use crate::prelude::*;
+use fnv::FnvHashSet;
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct NewickNode {
+    pub label: Option<String>,
+    pub hybrid: Option<String>,
+    pub children: Vec<NewickNode>,
+}
+
+struct Parser<'t> {
+    text: &'t [u8],
+    offset: usize,
+}
+
+impl<'t> Parser<'t> {
+    fn skip_whitespace(&mut self) -> Result<()> {
+        while let Some(&c) = self.text.get(self.offset) {
+            if c.is_ascii_whitespace() {
+                self.offset += 1;
+            } else if c == b'[' {
+                // comments, e.g. [&R] or [support=0.9]
+                match self.text[self.offset..].iter().position(|&c| c == b']') {
+                    Some(end) => self.offset += end + 1,
+                    None => bail!("Unterminated comment at offset {}", self.offset),
+                }
+            } else {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn peek(&mut self) -> Result<Option<u8>> {
+        self.skip_whitespace()?;
+        Ok(self.text.get(self.offset).copied())
+    }
+
+    fn expect(&mut self, expected: u8) -> Result<()> {
+        match self.peek()? {
+            Some(c) if c == expected => {
+                self.offset += 1;
+                Ok(())
+            }
+            Some(c) => bail!(
+                "Expected '{}' but found '{}' at offset {}",
+                char::from(expected),
+                char::from(c),
+                self.offset
+            ),
+            None => bail!("Expected '{}' but found end of input", char::from(expected)),
+        }
+    }
+
+    fn label(&mut self) -> Result<Option<String>> {
+        if self.peek()? == Some(b'\'') {
+            self.offset += 1;
+            let mut label = Vec::new();
+            loop {
+                match self.text.get(self.offset) {
+                    Some(b'\'') if self.text.get(self.offset + 1) == Some(&b'\'') => {
+                        label.push(b'\'');
+                        self.offset += 2;
+                    }
+                    Some(b'\'') => {
+                        self.offset += 1;
+                        break;
+                    }
+                    Some(&c) => {
+                        label.push(c);
+                        self.offset += 1;
+                    }
+                    None => bail!("Unterminated quoted label"),
+                }
+            }
+            return Ok(Some(String::from_utf8(label)?));
+        }
+
+        let start = self.offset;
+        while let Some(&c) = self.text.get(self.offset) {
+            if c.is_ascii_whitespace() || b"()[]':;,#".contains(&c) {
+                break;
+            }
+            self.offset += 1;
+        }
+        if start == self.offset {
+            Ok(None)
+        } else {
+            Ok(Some(
+                std::str::from_utf8(&self.text[start..self.offset])?.to_owned(),
+            ))
+        }
+    }
+
+    fn node(&mut self) -> Result<NewickNode> {
+        let mut node = NewickNode::default();
+        if self.peek()? == Some(b'(') {
+            self.offset += 1;
+            node.children.push(self.node()?);
+            while self.peek()? == Some(b',') {
+                self.offset += 1;
+                node.children.push(self.node()?);
+            }
+            self.expect(b')')?;
+        }
+
+        node.label = self.label()?;
+        if self.peek()? == Some(b'#') {
+            self.offset += 1;
+            node.hybrid = self.label()?;
+            if node.hybrid.is_none() {
+                bail!("Expected hybrid name after '#' at offset {}", self.offset);
+            }
+        }
+
+        if self.peek()? == Some(b':') {
+            // branch lengths do not mean anything to us
+            self.offset += 1;
+            self.label()?;
+        }
+
+        Ok(node)
+    }
+}
+
+pub fn parse_newick(text: &str) -> Result<NewickNode> {
+    let mut parser = Parser {
+        text: text.as_bytes(),
+        offset: 0,
+    };
+    let node = parser.node()?;
+    parser.expect(b';')?;
+    if parser.peek()?.is_some() {
+        bail!("Unexpected data after ';' at offset {}", parser.offset);
+    }
+    Ok(node)
+}
+
+fn quote_label(label: &str) -> String {
+    if label
+        .chars()
+        .any(|c| c.is_whitespace() || "()[]':;,#".contains(c))
+    {
+        format!("'{}'", label.replace('\'', "''"))
+    } else {
+        label.to_owned()
+    }
+}
+
+impl<'a> Graph<'a> {
+    /// Builds a tree from a parsed Newick tree whose leaves name the sequences.
+    pub fn from_newick(
+        amino_acid_model: &'a AminoAcidModel,
+        alignment: &'a Alignment,
+        tree: &NewickNode,
+    ) -> Result<Self> {
+        let mut graph = Graph::new(amino_acid_model, alignment);
+        let root = graph.root();
+
+        let mut leaves = FnvHashMap::default();
+        for node_id in graph.node_ids().collect_vec() {
+            if let NodeKind::Leaf(sequence_id) = graph[node_id].kind {
+                leaves.insert(alignment.sequence_ids[sequence_id].as_str(), node_id);
+                graph.remove_edge(node_id, root);
+            }
+        }
+
+        // a root with a single child would be penalized, so root at the child
+        let mut tree = tree;
+        while tree.children.len() == 1 && !tree.children[0].children.is_empty() {
+            tree = &tree.children[0];
+        }
+
+        let mut builder = NewickBuilder {
+            graph,
+            leaves,
+            hybrids: FnvHashMap::default(),
+            built: FnvHashMap::default(),
+            building: FnvHashSet::default(),
+            order: Vec::new(),
+        };
+        builder.find_hybrids(tree)?;
+        for child in &tree.children {
+            builder.build(child, root)?;
+        }
+
+        let mut graph = builder.graph;
+        for node_id in graph.node_ids() {
+            if let NodeKind::Leaf(sequence_id) = graph[node_id].kind {
+                if graph[node_id].parents.is_empty() {
+                    bail!(
+                        "Sequence {} does not appear in the tree",
+                        alignment.sequence_ids[sequence_id]
+                    );
+                }
+            }
+        }
+
+        // children come before their parents, so ancestral states are pushed up
+        // from the leaves
+        for node_id in builder.order {
+            analyze_amino_acids(&mut graph, node_id, 0, &mut NullTracer);
+        }
+
+        Ok(graph)
+    }
+
+    pub fn newick_label(&self, node_id: NodeId) -> String {
+        match self[node_id].kind {
+            NodeKind::Leaf(leaf) => self.alignment().sequence_ids[leaf].clone(),
+            NodeKind::Root | NodeKind::Other => format!("N{}", node_id.0),
+        }
+    }
+
+    // Plain Newick for trees, Extended Newick for DAGs: a node with several
+    // parents is written in full under the first parent visited and as a bare
+    // #H reference under the rest.
+    pub fn newick(&self) -> String {
+        self.newick_annotated(|_, _| None)
+    }
+
+    /// Newick with a comment, such as `[&support=0.9]`, after each node for
+    /// the edge to the parent it is written under.
+    pub fn newick_annotated(
+        &self,
+        annotation: impl Fn(NodeId, NodeId) -> Option<String>,
+    ) -> String {
+        let mut hybrids = FnvHashMap::default();
+        for node_id in self.node_ids() {
+            if self[node_id].parents.len() > 1 {
+                let hybrid = hybrids.len() + 1;
+                hybrids.insert(node_id, hybrid);
+            }
+        }
+
+        let mut output = String::new();
+        let mut seen = SlabSet::new_with_capacity_of(self.nodes());
+        self.write_newick_node(
+            self.root(),
+            None,
+            &hybrids,
+            &annotation,
+            &mut seen,
+            &mut output,
+        );
+        output.push(';');
+        output
+    }
+
+    fn write_newick_node(
+        &self,
+        node_id: NodeId,
+        parent: Option<NodeId>,
+        hybrids: &FnvHashMap<NodeId, usize>,
+        annotation: &impl Fn(NodeId, NodeId) -> Option<String>,
+        seen: &mut SlabSet<NodeId>,
+        output: &mut String,
+    ) {
+        if seen.insert(node_id) {
+            let children = self[node_id].children.iter().copied().sorted();
+            if !self[node_id].children.is_empty() {
+                output.push('(');
+                for (index, child) in children.enumerate() {
+                    if index > 0 {
+                        output.push(',');
+                    }
+                    self.write_newick_node(child, Some(node_id), hybrids, annotation, seen, output);
+                }
+                output.push(')');
+            }
+            output.push_str(&quote_label(&self.newick_label(node_id)));
+        }
+
+        if let Some(hybrid) = hybrids.get(&node_id) {
+            output.push_str(&format!("#H{}", hybrid));
+        }
+        if let Some(comment) = parent.and_then(|parent| annotation(node_id, parent)) {
+            output.push_str(&format!("[{}]", comment));
+        }
+    }
+}
+
+struct NewickBuilder<'g, 'a> {
+    graph: Graph<'a>,
+    leaves: FnvHashMap<&'g str, NodeId>,
+    hybrids: FnvHashMap<&'g str, &'g NewickNode>,
+    built: FnvHashMap<*const NewickNode, NodeId>,
+    building: FnvHashSet<*const NewickNode>,
+    order: Vec<NodeId>,
+}
+
+// nodes with a single child would be penalized, so skip them
+fn collapse(mut node: &NewickNode) -> &NewickNode {
+    while node.children.len() == 1 {
+        node = &node.children[0];
+    }
+    node
+}
+
+fn is_hybrid_reference(node: &NewickNode) -> bool {
+    node.hybrid.is_some() && node.label.is_none() && node.children.is_empty()
+}
+
+impl<'g, 'a> NewickBuilder<'g, 'a> {
+    // a hybrid may be referenced before the occurrence that defines it, so
+    // find all the definitions first
+    fn find_hybrids(&mut self, node: &'g NewickNode) -> Result<()> {
+        if let Some(hybrid) = node.hybrid.as_deref() {
+            if !is_hybrid_reference(node) {
+                let definition = collapse(node);
+                match self.hybrids.get(hybrid) {
+                    Some(existing) if !existing.children.is_empty() => {
+                        if !definition.children.is_empty() {
+                            bail!("Hybrid #{} has children in more than one place", hybrid);
+                        }
+                    }
+                    _ => {
+                        self.hybrids.insert(hybrid, definition);
+                    }
+                }
+            }
+        }
+        for child in &node.children {
+            self.find_hybrids(child)?;
+        }
+        Ok(())
+    }
+
+    fn build(&mut self, node: &'g NewickNode, parent: NodeId) -> Result<()> {
+        let hybrid = node.hybrid.as_deref();
+        let node = match hybrid {
+            Some(hybrid) => *self
+                .hybrids
+                .get(hybrid)
+                .ok_or_else(|| eyre!("Hybrid #{} is never defined", hybrid))?,
+            None => collapse(node),
+        };
+
+        if let Some(&node_id) = self.built.get(&(node as *const NewickNode)) {
+            if self.building.contains(&(node as *const NewickNode)) {
+                bail!("Hybrid #{} is its own ancestor", hybrid.unwrap_or_default());
+            }
+            self.graph.add_edge(node_id, parent);
+            return Ok(());
+        }
+
+        let node_id = if node.children.is_empty() {
+            let label = node
+                .label
+                .as_deref()
+                .ok_or_else(|| eyre!("Tree has a leaf without a label"))?;
+            let node_id = *self
+                .leaves
+                .get(label)
+                .ok_or_else(|| eyre!("Tree leaf {} is not in the alignment", label))?;
+            if !self.graph[node_id].parents.is_empty() {
+                bail!("Tree leaf {} appears more than once", label);
+            }
+            node_id
+        } else {
+            self.graph.create_node(self.graph.root())
+        };
+        self.built.insert(node, node_id);
+        self.graph.add_edge(node_id, parent);
+
+        self.building.insert(node);
+        for child in &node.children {
+            self.build(child, node_id)?;
+        }
+        self.building.remove(&(node as *const NewickNode));
+        self.order.push(node_id);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const ALIGNMENT: &[u8] = b">s1\nAWTK\n>s2\nAWSR\n>odd,name\nCWSR\n";
+
+    #[test]
+    fn test_quote_label() {
+        assert_eq!(quote_label("s1"), "s1");
+        assert_eq!(quote_label("Homo sapiens"), "'Homo sapiens'");
+        assert_eq!(quote_label("it's"), "'it''s'");
+    }
+
+    #[test]
+    fn test_parse_newick() -> Result<()> {
+        let tree = parse_newick("((A:0.1,'B c':0.2)90:0.3,[&R] C)root;")?;
+        assert_eq!(tree.label.as_deref(), Some("root"));
+        assert_eq!(tree.children.len(), 2);
+        assert_eq!(tree.children[0].label.as_deref(), Some("90"));
+        assert_eq!(tree.children[0].children[1].label.as_deref(), Some("B c"));
+        assert_eq!(tree.children[1].label.as_deref(), Some("C"));
+
+        let network = parse_newick("(A#H1,(B,#H1)X);")?;
+        assert_eq!(network.children[0].hybrid.as_deref(), Some("H1"));
+        assert_eq!(network.children[1].children[1].label, None);
+        assert_eq!(
+            network.children[1].children[1].hybrid.as_deref(),
+            Some("H1")
+        );
+
+        assert!(parse_newick("(A,B").is_err());
+        assert!(parse_newick("(A,B);C").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_newick() -> Result<()> {
+        let amino_acid_model = crate::amino_acids::read_paml_matrix(std::io::Cursor::new(
+            include_bytes!("./BLOSUM62.paml"),
+        ))?;
+        let alignment =
+            crate::alignment::read_alignment(std::io::Cursor::new(ALIGNMENT), &amino_acid_model)?;
+
+        let graph = Graph::from_newick(
+            &amino_acid_model,
+            &alignment,
+            &parse_newick("(((s1,s2#H1)X),'odd,name',#H1);")?,
+        )?;
+        assert_eq!(graph.newick(), "(s2#H1,'odd,name',(s1,#H1)N4)N0;");
+
+        // reference before definition, and a tag on a collapsed unary node
+        for tree in &[
+            "(#H1,(s1,s2#H1),'odd,name');",
+            "(((s2))#H1,(s1,#H1),'odd,name');",
+        ] {
+            let graph = Graph::from_newick(&amino_acid_model, &alignment, &parse_newick(tree)?)?;
+            assert_eq!(graph.newick(), "(s2#H1,'odd,name',(s1,#H1)N4)N0;");
+        }
+        assert!(Graph::from_newick(
+            &amino_acid_model,
+            &alignment,
+            &parse_newick("(#H2,(s1,s2),'odd,name');")?
+        )
+        .is_err());
+        match Graph::from_newick(
+            &amino_acid_model,
+            &alignment,
+            &parse_newick("((s1,#H1)#H1,s2,'odd,name');")?,
+        ) {
+            Err(error) => assert_eq!(error.to_string(), "Hybrid #H1 is its own ancestor"),
+            Ok(_) => panic!("cyclic hybrid accepted"),
+        }
+
+        assert!(
+            Graph::from_newick(&amino_acid_model, &alignment, &parse_newick("(s1,s2);")?).is_err()
+        );
+        assert!(Graph::from_newick(
+            &amino_acid_model,
+            &alignment,
+            &parse_newick("(s1,s2,s3,'odd,name');")?
+        )
+        .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_newick() {
+        let amino_acid_model = crate::amino_acids::read_paml_matrix(std::io::Cursor::new(
+            include_bytes!("./BLOSUM62.paml"),
+        ))
+        .unwrap();
+        let alignment =
+            crate::alignment::read_alignment(std::io::Cursor::new(ALIGNMENT), &amino_acid_model)
+                .unwrap();
+
+        let mut graph = Graph::new(&amino_acid_model, &alignment);
+        assert_eq!(graph.newick(), "(s1,s2,'odd,name')N0;");
+
+        let root = graph.root();
+        let node = graph.create_node(root);
+        graph.add_edge(node, root);
+        graph.remove_edge(NodeId(1), root);
+        graph.remove_edge(NodeId(2), root);
+        graph.add_edge(NodeId(1), node);
+        graph.add_edge(NodeId(2), node);
+        assert_eq!(graph.newick(), "('odd,name',(s1,s2)N4)N0;");
+
+        graph.add_edge(NodeId(2), root);
+        assert_eq!(graph.newick(), "(s2#H1,'odd,name',(s1,#H1)N4)N0;");
+    }
+}
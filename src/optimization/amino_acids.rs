@@ -94,7 +94,9 @@ impl<'a, T: Tracer> FloodContext<'a, T> {
                         current_inherits.changes(amino_acid)
                     );
                 }*/
-                new_inherits.changes(amino_acid) - current_inherits.changes(amino_acid)
+                let model = self.graph.amino_acid_model();
+                new_inherits.changes(model.resolve(amino_acid, new_inherits.0))
+                    - current_inherits.changes(model.resolve(amino_acid, current_inherits.0))
             } else {
                 self.flood(new_inherits, child, new_amino_acid, changes)
             };
@@ -178,7 +180,9 @@ pub fn analyze_amino_acids(graph: &mut Graph, node: NodeId, bias: i32, tracer: &
 
     'position: for position in graph.alignment().positions.ids() {
         let inherited = graph.inherited_for_position(node, position);
-        let actual = graph[node].amino_acids[position].amino_acid;
+        let actual = graph
+            .amino_acid_model()
+            .resolve(graph[node].amino_acids[position].amino_acid, inherited.0);
 
         if inherited.0 != actual {
             tracer.open_ex(|| format!("{:?}", position.0));
@@ -466,6 +470,20 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_ambiguous_leaf() {
+        check(
+            "
+        1 B -> 6
+        2 B -> 6
+        *3 D -> 6
+        4 T -> 6
+        5 T -> 6
+        6 T>D
+        ",
+        );
+    }
+
     #[test]
     fn test_down() {
         check(
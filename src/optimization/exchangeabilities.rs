@@ -0,0 +1,110 @@
+use crate::{amino_acids::AminoAcidModel, prelude::*};
+
+// transitions are scored by exp(rate_matrix * parameter), which is symmetric, so
+// the symmetrized counts are rescaled into a symmetric doubly stochastic matrix
+// and its matrix logarithm gives the exchangeabilities
+pub fn estimate_exchangeabilities(
+    model: &AminoAcidModel,
+    parameter: R64,
+    counts: &AminoAcidMatrix<i32>,
+) -> AminoAcidMatrix<f64> {
+    // one pseudocount per cell, spread the way the current model expects, keeps
+    // substitutions that were never inferred possible
+    let prior = (model.rate_matrix * parameter.raw()).exp() * 20.0;
+    let counts = counts.map(f64::from);
+    let observed = (counts + counts.transpose()) / 2.0 + prior;
+
+    let mut scale = nalgebra::SVector::<f64, 20>::repeat(1.0);
+    for _ in 0..1000 {
+        let sums = observed * scale;
+        scale = scale.zip_map(&sums, |scale, sum| (scale / sum).sqrt());
+    }
+    let probabilities = AminoAcidMatrix::from_fn(|row, column| {
+        scale[row] * observed[(row, column)] * scale[column]
+    });
+
+    let eigen = nalgebra::SymmetricEigen::new(probabilities);
+    let logarithms = eigen
+        .eigenvalues
+        .map(|eigenvalue| eigenvalue.clamp(1e-6, 1.0).ln());
+    let logarithm = eigen.eigenvectors
+        * AminoAcidMatrix::from_diagonal(&logarithms)
+        * eigen.eigenvectors.transpose();
+
+    let mut exchangeabilities = AminoAcidMatrix::zeros();
+    for row in 0..20 {
+        for column in 0..row {
+            let rate = (logarithm[(row, column)] + logarithm[(column, row)]) / 2.0;
+            let rate = rate.max(1e-6) / parameter.raw();
+            exchangeabilities[(row, column)] = rate;
+            exchangeabilities[(column, row)] = rate;
+            exchangeabilities[(row, row)] -= rate;
+            exchangeabilities[(column, column)] -= rate;
+        }
+    }
+    exchangeabilities
+}
+
+pub fn estimate_model(graph: &mut Graph) -> AminoAcidModel {
+    graph.ensure_clean();
+    let rate_matrix = estimate_exchangeabilities(
+        graph.amino_acid_model(),
+        graph.parameter(),
+        &graph.stats.transitions,
+    );
+    let fitted = graph
+        .amino_acid_model()
+        .with_frequencies(&super::insert_counts(graph));
+    AminoAcidModel {
+        rate_matrix,
+        initial_probabilities: fitted.initial_probabilities,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_estimate_exchangeabilities() {
+        let model = crate::amino_acids::read_paml_matrix(std::io::Cursor::new(include_bytes!(
+            "../BLOSUM62.paml"
+        )))
+        .unwrap();
+        let parameter = r64(0.1);
+
+        // counts drawn exactly as the model expects give back the model
+        let expected = (model.rate_matrix * parameter.raw()).exp();
+        let counts = expected.map(|x| (x * 100_000.0).round() as i32);
+        let estimated = estimate_exchangeabilities(&model, parameter, &counts);
+        for row in 0..20 {
+            for column in 0..20 {
+                let difference = estimated[(row, column)] - model.rate_matrix[(row, column)];
+                assert!(
+                    difference.abs() < 0.02 * model.rate_matrix[(row, column)].abs() + 1e-3,
+                    "{} {} {} {}",
+                    row,
+                    column,
+                    estimated[(row, column)],
+                    model.rate_matrix[(row, column)]
+                );
+            }
+        }
+
+        // substitutions that happen more often than the model expects get
+        // larger exchangeabilities
+        let mut counts = counts;
+        counts[(0, 1)] += 5_000;
+        counts[(1, 0)] += 5_000;
+        let estimated = estimate_exchangeabilities(&model, parameter, &counts);
+        assert!(estimated[(0, 1)] > model.rate_matrix[(0, 1)] * 1.5);
+
+        let fitted = model.parameterize(parameter);
+        let refitted = AminoAcidModel {
+            rate_matrix: estimated,
+            initial_probabilities: model.initial_probabilities,
+        }
+        .parameterize(parameter);
+        assert!(refitted.likelihood(&counts) > fitted.likelihood(&counts));
+    }
+}
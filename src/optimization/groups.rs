@@ -44,11 +44,15 @@ pub fn optimize_groups(graph: &mut Graph) {
 
     for node_id in graph.node_ids() {
         for (index, amino_acid) in graph[node_id].amino_acids.iter() {
-            if amino_acid.inherited.unwrap().0 != amino_acid.amino_acid && amino_acid.amino_acid != AminoAcid::Unknown {
+            let inherited = amino_acid.inherited.unwrap().0;
+            let actual = graph
+                .amino_acid_model()
+                .resolve(amino_acid.amino_acid, inherited);
+            if inherited != actual && actual != AminoAcid::Unknown {
                 candidates.push((
                     index,
-                    amino_acid.inherited.unwrap().0,
-                    amino_acid.amino_acid,
+                    inherited,
+                    actual,
                     node_id,
                 ));
             }
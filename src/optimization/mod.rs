@@ -1,4 +1,5 @@
 mod amino_acids;
+mod exchangeabilities;
 pub mod groups;
 pub mod moves;
 mod nn_join;
@@ -7,16 +8,20 @@ mod parameter;
 pub use groups::optimize_groups;
 
 pub use amino_acids::analyze_amino_acids;
+pub use exchangeabilities::estimate_model;
 pub use nn_join::nn_join;
-pub use parameter::optimize_parameter;
+pub use parameter::{insert_counts, optimize_parameter};
 
 
 use crate::prelude::*;
+/// One round of hill climbing: graph moves, grouping shared substitutions and
+/// refitting the model parameter.
 pub fn optimize(graph: &mut Graph) -> Vec<moves::MoveLog> {
-	let made_moves = moves::optimize(graph);
+	let mut made_moves = moves::optimize(graph);
 	optimize_groups(graph);
 	optimize_parameter(graph);
 	graph.compact();
+	made_moves.push(moves::MoveLog::refit(graph));
 
 	made_moves
 }
\ No newline at end of file
@@ -44,7 +44,8 @@ impl Guide {
     }
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Reformation)]
+/// A change to the graph's edges, written as in `moves.log`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Reformation, Serialize, Deserialize)]
 pub enum GraphMove {
     #[reformation("refactor:{},{}")]
     Refactor(NodeId, NodeId),
@@ -72,6 +73,65 @@ impl std::str::FromStr for GraphMove {
     }
 }
 
+// written in the syntax it is parsed from
+impl std::fmt::Display for GraphMove {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            GraphMove::Refactor(lhs, rhs) => write!(f, "refactor:N{},N{}", lhs.0, rhs.0),
+            GraphMove::Remove(node) => write!(f, "remove:N{}", node.0),
+            GraphMove::AddEdge(lhs, rhs) => write!(f, "add-edge:N{}-N{}", lhs.0, rhs.0),
+            GraphMove::RemoveEdge(source, destination) => {
+                write!(f, "remove-edge:N{}-N{}", source.0, destination.0)
+            }
+            GraphMove::ChangeEdge(source, destination, new_destination) => write!(
+                f,
+                "change-edge:N{}-N{},N{}",
+                source.0, destination.0, new_destination.0
+            ),
+            GraphMove::Reparent(child, parent) => {
+                write!(f, "reparent:N{}-N{}", child.0, parent.0)
+            }
+            GraphMove::SetAminoAcid(node, index, amino_acid) => write!(
+                f,
+                "set-amino-acid:N{}@{}={}",
+                node.0,
+                index.0,
+                char::from(amino_acid.as_u8())
+            ),
+            GraphMove::FloodFill(node, index, amino_acid) => write!(
+                f,
+                "flood:N{}@{}={}",
+                node.0,
+                index.0,
+                char::from(amino_acid.as_u8())
+            ),
+        }
+    }
+}
+
+impl GraphMove {
+    /// Reads the position of a move written against alignment columns, as
+    /// moves given on the command line are.
+    pub fn with_alignment_columns(self, alignment: &Alignment) -> GraphMove {
+        match self {
+            GraphMove::SetAminoAcid(node, index, amino_acid) => GraphMove::SetAminoAcid(
+                node,
+                alignment.position_index(index.0 as usize),
+                amino_acid,
+            ),
+            GraphMove::FloodFill(node, index, amino_acid) => {
+                GraphMove::FloodFill(node, alignment.position_index(index.0 as usize), amino_acid)
+            }
+            other => other,
+        }
+    }
+
+    /// Whether the move can be applied to the graph as it stands.
+    pub fn is_valid(self, graph: &Graph) -> bool {
+        self.valid(graph, &Guide::new(graph))
+    }
+}
+
 fn generate_move(graph: &Graph, random: &mut Random) -> GraphMove {
     match random.gen_range(0..7) {
         0 => {
@@ -433,25 +493,79 @@ pub fn shuffle(graph: &mut Graph, random: &mut Random, count: usize) -> Vec<Move
         }
         let new_graph = mutated(graph, selected_move, &current_guide);
         *graph = new_graph;
-        moves.push(MoveLog {
-            the_move: selected_move,
-            probability: graph.probability(),
-            kind: MoveLogKind::Random,
-        });
+        moves.push(MoveLog::new(selected_move, graph, MoveLogKind::Random));
     }
     moves
 }
 
-#[derive(Debug)]
+/// Makes `steps` random moves, accepting each with the Metropolis rule:
+/// always if it improves the graph, otherwise with probability
+/// exp(Δbits / temperature).
+pub fn anneal(
+    graph: &mut Graph,
+    random: &mut Random,
+    temperature: f64,
+    steps: usize,
+) -> Vec<MoveLog> {
+    let mut moves = Vec::new();
+    for _ in 0..steps {
+        let current_guide = Guide::new(graph);
+        let mut selected_move = generate_move(graph, random);
+        while !selected_move.valid(graph, &current_guide) {
+            selected_move = generate_move(graph, random)
+        }
+        let mut new_graph = mutated(graph, selected_move, &current_guide);
+        let bits = (new_graph.probability().log2() - graph.probability().log2()).raw();
+        if bits >= 0.0 || random.gen::<f64>() < (bits / temperature).exp() {
+            *graph = new_graph;
+            moves.push(MoveLog::new(selected_move, graph, MoveLogKind::Annealing));
+        }
+    }
+    moves
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum MoveLogKind {
+    #[serde(alias = "Climbing")]
     Climbing,
+    #[serde(alias = "Random")]
     Random,
+    // groups and the parameter were refitted and the nodes compacted, which
+    // has no move
+    Refit,
+    Annealing,
 }
 
+#[derive(Clone, Serialize, Deserialize)]
 pub struct MoveLog {
-    pub the_move: GraphMove,
+    pub the_move: Option<GraphMove>,
     pub probability: Log,
     pub kind: MoveLogKind,
+    #[serde(default)]
+    pub node_count: usize,
+    #[serde(default)]
+    pub edge_count: u32,
+}
+
+impl MoveLog {
+    fn new(the_move: GraphMove, graph: &mut Graph, kind: MoveLogKind) -> MoveLog {
+        MoveLog::logged(Some(the_move), graph, kind)
+    }
+
+    pub fn refit(graph: &mut Graph) -> MoveLog {
+        MoveLog::logged(None, graph, MoveLogKind::Refit)
+    }
+
+    fn logged(the_move: Option<GraphMove>, graph: &mut Graph, kind: MoveLogKind) -> MoveLog {
+        MoveLog {
+            the_move,
+            probability: graph.probability(),
+            kind,
+            node_count: graph.nodes().len(),
+            edge_count: graph.edge_count(),
+        }
+    }
 }
 
 pub fn optimize(graph: &mut Graph) -> Vec<MoveLog> {
@@ -476,11 +590,7 @@ pub fn optimize(graph: &mut Graph) -> Vec<MoveLog> {
             if new_graph.probability() > graph.probability() {
                 *graph = new_graph;
                 current_guide = Guide::new(graph);
-                logs.push(MoveLog {
-                    the_move: m,
-                    probability: graph.probability(),
-                    kind: MoveLogKind::Climbing,
-                })
+                logs.push(MoveLog::new(m, graph, MoveLogKind::Climbing))
             }
         }
     }
@@ -546,3 +656,41 @@ pub fn debug_move(graph: &mut Graph, the_move: GraphMove, tracer: &mut impl Trac
     }
     */
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_anneal() -> Result<()> {
+        let model = crate::amino_acids::read_paml_matrix(std::io::Cursor::new(include_bytes!(
+            "../BLOSUM62.paml"
+        )))?;
+        let alignment = crate::alignment::read_alignment(
+            std::io::Cursor::new(">a\nAWTKC\n>b\nAWSRC\n>c\nCWSRD\n>d\nCFSRD\n>e\nAFTKC\n"),
+            &model,
+        )?;
+        let mut random = Random::seed_from_u64(7);
+        let mut graph = Graph::new(&model, &alignment);
+        crate::optimization::nn_join(&mut graph);
+        optimize(&mut graph);
+
+        // nearly cold annealing only takes moves that do not make the graph worse
+        let mut cold = graph.clone();
+        let mut probability = cold.probability();
+        for made_move in anneal(&mut cold, &mut random, 1e-9, 30) {
+            assert!(made_move.probability >= probability);
+            probability = made_move.probability;
+        }
+
+        // hot annealing wanders downhill from the optimum
+        let mut hot = graph.clone();
+        let made_moves = anneal(&mut hot, &mut random, 1e9, 30);
+        assert_eq!(made_moves.len(), 30);
+        assert!(made_moves
+            .iter()
+            .all(|made_move| made_move.kind == MoveLogKind::Annealing));
+        assert!(hot.probability() < graph.probability());
+        Ok(())
+    }
+}
@@ -2,6 +2,7 @@ use crate::prelude::*;
 
 use super::amino_acids::analyze_amino_acids;
 
+/// Turns a star phylogeny into a nearest neighbor joining tree.
 pub fn nn_join(graph: &mut Graph) {
     let root = graph.root();
     loop {
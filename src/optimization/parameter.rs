@@ -1,6 +1,10 @@
 use argmin::prelude::ArgminOp;
 
-use crate::{amino_acids::AminoAcidModel, prelude::*};
+use crate::{
+    alignment::RawPosition,
+    amino_acids::{AminoAcidModel, SiteRates, SiteTransitions},
+    prelude::*,
+};
 use argmin::prelude::*;
 
 struct Problem<'a> {
@@ -30,7 +34,117 @@ impl<'a> ArgminOp for Problem<'a> {
     }
 }
 
+// the shape and invariant proportion are searched on log and logit scales so
+// that every point the simplex visits is valid
+struct RatesProblem<'a> {
+    amino_acid_model: &'a AminoAcidModel,
+    rates: SiteRates,
+    sites: SiteTransitions,
+}
+
+impl<'a> RatesProblem<'a> {
+    fn initial_params(&self, parameter: R64) -> Vec<f64> {
+        let mut param = vec![parameter.raw()];
+        if self.rates.gamma_categories > 0 {
+            param.push(self.rates.shape.raw().ln());
+        }
+        if let Some(invariant) = self.rates.invariant {
+            param.push((invariant.raw() / (1.0 - invariant.raw())).ln());
+        }
+        param
+    }
+
+    fn decode(&self, param: &[f64]) -> Option<(R64, SiteRates)> {
+        decode_rates(self.rates, param)
+    }
+}
+
+fn decode_rates(mut rates: SiteRates, param: &[f64]) -> Option<(R64, SiteRates)> {
+    let mut rest = param[1..].iter();
+    if rates.gamma_categories > 0 {
+        rates.shape = R64::try_new(rest.next().unwrap().exp())?;
+    }
+    if rates.invariant.is_some() {
+        rates.invariant = Some(R64::try_new(1.0 / (1.0 + (-rest.next().unwrap()).exp()))?);
+    }
+    let shape_usable = rates.shape > 1e-3 && rates.shape < 1e3;
+    let invariant_usable = match rates.invariant {
+        Some(invariant) => invariant > 1e-6 && invariant < 1.0 - 1e-6,
+        None => true,
+    };
+    if param[0] < 0.0 || !shape_usable || !invariant_usable {
+        return None;
+    }
+    Some((r64(param[0]), rates))
+}
+
+impl<'a> ArgminOp for RatesProblem<'a> {
+    type Param = Vec<f64>;
+    type Output = f64;
+    type Hessian = ();
+    type Jacobian = ();
+    type Float = f64;
+
+    fn apply(&self, param: &Vec<f64>) -> Result<f64, Error> {
+        let (parameter, rates) = match self.decode(param) {
+            Some(decoded) => decoded,
+            None => return Ok(f64::INFINITY),
+        };
+        let result: N64 = self
+            .amino_acid_model
+            .parameterize_with_rates(parameter, rates)
+            .site_likelihood(&self.sites)
+            .log2();
+
+        Ok(-result.raw())
+    }
+}
+
+fn optimize_site_rates(graph: &mut Graph) {
+    let rates = graph.site_rates();
+    let operator = RatesProblem {
+        amino_acid_model: graph.amino_acid_model(),
+        rates,
+        sites: graph.site_transitions(),
+    };
+
+    let initial = operator.initial_params(graph.parameter());
+    let mut simplex = vec![initial.clone()];
+    for dimension in 0..initial.len() {
+        let mut vertex = initial.clone();
+        vertex[dimension] += 0.1;
+        simplex.push(vertex);
+    }
+    let solver = argmin::solver::neldermead::NelderMead::new()
+        .with_initial_params(simplex)
+        .sd_tolerance(0.1);
+
+    let baseline = graph.parameterized_model().site_likelihood(&operator.sites);
+
+    // a failed contraction never shrinks the simplex, so the search can stall
+    // short of the tolerance
+    let result = Executor::new(operator, solver, initial)
+        .max_iters(1000)
+        .run()
+        .unwrap();
+
+    let state = result.state();
+
+    if Log::pow2(n64(-state.best_cost)) > baseline {
+        if let Some((parameter, rates)) = decode_rates(rates, &state.best_param) {
+            graph.set_model(parameter, rates);
+        }
+    }
+}
+
+/// Fits the substitution rate scale, and the site rate distribution if any, to
+/// the substitutions in the graph.
 pub fn optimize_parameter(graph: &mut Graph) {
+    if !graph.site_rates().is_uniform() {
+        optimize_site_rates(graph);
+        return;
+    }
+
     let solver = argmin::solver::neldermead::NelderMead::new().with_initial_params(vec![
         vec![graph.parameter().raw()],
         vec![graph.parameter().raw() - 0.1],
@@ -55,3 +169,43 @@ pub fn optimize_parameter(graph: &mut Graph) {
         graph.set_parameter(r64(state.best_param[0]));
     }
 }
+
+// frequencies only enter the score through residues at the root and inserted
+// residues, so for a fixed graph the best frequencies are their composition
+pub fn insert_counts(graph: &mut Graph) -> [f64; 20] {
+    graph.ensure_derived();
+    let model = graph.amino_acid_model();
+
+    let mut counts = [0.0; 20];
+    let mut record = |amino_acid: AminoAcid| {
+        if amino_acid.is_amino_acid() {
+            counts[amino_acid.as_index().unwrap()] += 1.0;
+        }
+    };
+
+    for node_id in graph.node_ids() {
+        let node = &graph[node_id];
+        for amino_acid in node.amino_acids.values() {
+            if node.kind == NodeKind::Root {
+                record(amino_acid.amino_acid);
+            } else if amino_acid.inherited.unwrap().0 == AminoAcid::Gap {
+                record(model.resolve(amino_acid.amino_acid, AminoAcid::Gap));
+            }
+        }
+    }
+
+    // positions the alignment scores by itself
+    for raw in graph.alignment().raw_positions.values() {
+        if let RawPosition::Simple(reference, sequences) = raw {
+            if *reference == AminoAcid::Gap {
+                for &amino_acid in sequences.values() {
+                    record(model.resolve(amino_acid, AminoAcid::Gap));
+                }
+            } else {
+                record(*reference);
+            }
+        }
+    }
+
+    counts
+}
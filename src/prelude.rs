@@ -17,7 +17,7 @@ pub use fnv::FnvHashMap;
 pub use rand::Rng;
 pub use rayon::iter::ParallelIterator;
 pub use sorted_vec::SortedSet;
-pub type Random = rand::rngs::StdRng;
+pub type Random = rand_chacha::ChaCha12Rng;
 
 pub use crate::alignment::{read_alignment, Alignment, PositionData, PositionIndex, SequenceId};
 pub use crate::amino_acids::{AminoAcid, AminoAcidMap};
@@ -0,0 +1,533 @@
+//! Metropolis–Hastings sampling of graphs from their posterior probability.
+//!
+//! The chain keeps the nodes of the graph it starts from and the fitted
+//! parameter and site rates. It proposes regrafting ancestors, moving, adding
+//! and removing edges and changing ancestral residues, so the number of
+//! ancestors is not sampled.
+
+use crate::optimization::moves::Guide;
+use crate::prelude::*;
+use rand::prelude::*;
+
+pub const SAMPLES_LOG: &str = "samples.log";
+
+pub struct Chain<'a> {
+    graph: Graph<'a>,
+    guide: Guide,
+    random: Random,
+    // the chain samples the probability raised to 1 / heat
+    heat: f64,
+    pub proposed: u64,
+    pub accepted: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Proposal {
+    SetAminoAcid(NodeId, PositionIndex, AminoAcid),
+    // an edge to a new parent
+    MoveEdge(NodeId, NodeId, NodeId),
+    AddEdge(NodeId, NodeId),
+    RemoveEdge(NodeId, NodeId),
+    // the parent of the node is pruned, its other child taking its place, and
+    // regrafted onto the edge between the other two nodes
+    Regraft(NodeId, NodeId, NodeId),
+}
+
+// ancestral residues may take any candidate of the position or a gap
+fn residues(graph: &Graph, position: PositionIndex) -> Vec<AminoAcid> {
+    let mut residues = graph.alignment().positions[position].candidates.clone();
+    if !residues.contains(&AminoAcid::Gap) {
+        residues.push(AminoAcid::Gap);
+    }
+    residues
+}
+
+fn edges<'g>(graph: &'g Graph) -> impl Iterator<Item = (NodeId, NodeId)> + 'g {
+    graph.node_ids().flat_map(move |node| {
+        graph[node]
+            .parents
+            .iter()
+            .map(move |&parent| (node, parent))
+    })
+}
+
+fn internal_nodes<'g>(graph: &'g Graph) -> impl Iterator<Item = NodeId> + Clone + 'g {
+    graph
+        .node_ids()
+        .filter(move |&node| !graph[node].kind.is_leaf())
+}
+
+fn non_root_nodes<'g>(graph: &'g Graph) -> impl Iterator<Item = NodeId> + Clone + 'g {
+    graph
+        .node_ids()
+        .filter(move |&node| graph[node].kind != NodeKind::Root)
+}
+
+// the pruned parent of a regrafted node and its other child
+fn pruned(graph: &Graph, node: NodeId) -> Option<(NodeId, NodeId)> {
+    match graph[node].parents[..] {
+        [parent] if graph[parent].parents.len() == 1 && graph[parent].children.len() == 2 => {
+            let sibling = graph[parent]
+                .children
+                .iter()
+                .copied()
+                .find(|&child| child != node)?;
+            Some((parent, sibling))
+        }
+        _ => None,
+    }
+}
+
+// proposals are drawn without regard to whether they can be made, and those
+// that cannot are rejected
+fn propose(graph: &Graph, random: &mut Random) -> Proposal {
+    match random.gen_range(0..5) {
+        0 => {
+            let node = internal_nodes(graph).choose(random).unwrap();
+            let position = graph.alignment().positions.ids().choose(random).unwrap();
+            let &amino_acid = residues(graph, position).choose(random).unwrap();
+            Proposal::SetAminoAcid(node, position, amino_acid)
+        }
+        1 => {
+            let (source, destination) = edges(graph).choose(random).unwrap();
+            let new_destination = internal_nodes(graph).choose(random).unwrap();
+            Proposal::MoveEdge(source, destination, new_destination)
+        }
+        2 => {
+            let source = non_root_nodes(graph).choose(random).unwrap();
+            let destination = internal_nodes(graph).choose(random).unwrap();
+            Proposal::AddEdge(source, destination)
+        }
+        3 => {
+            let (source, destination) = edges(graph).choose(random).unwrap();
+            Proposal::RemoveEdge(source, destination)
+        }
+        4 => {
+            let node = non_root_nodes(graph).choose(random).unwrap();
+            let (child, parent) = edges(graph).choose(random).unwrap();
+            Proposal::Regraft(node, child, parent)
+        }
+        _ => unreachable!(),
+    }
+}
+
+// every internal node keeps at least two children and every node but the root
+// at least one parent, so the proposals never leave nodes to clean up
+fn is_allowed(graph: &Graph, guide: &Guide, proposal: Proposal) -> bool {
+    match proposal {
+        Proposal::SetAminoAcid(node, position, amino_acid) => {
+            let residues = residues(graph, position);
+            let current = graph[node].amino_acids[position].amino_acid;
+            current != amino_acid && residues.contains(&current) && residues.contains(&amino_acid)
+        }
+        Proposal::MoveEdge(source, destination, new_destination) => {
+            !graph[new_destination].kind.is_leaf()
+                && !graph[source].parents.contains(&new_destination)
+                && !guide.reachable[new_destination].contains(source)
+                && graph[destination].children.len() > 2
+        }
+        Proposal::AddEdge(source, destination) => {
+            graph[source].kind != NodeKind::Root
+                && !graph[destination].kind.is_leaf()
+                && !graph[source].parents.contains(&destination)
+                && !guide.reachable[destination].contains(source)
+        }
+        Proposal::RemoveEdge(source, destination) => {
+            graph[source].parents.len() > 1 && graph[destination].children.len() > 2
+        }
+        Proposal::Regraft(node, child, parent) => match pruned(graph, node) {
+            Some((pruned, sibling)) => {
+                let grandparent = graph[pruned].parents[0];
+                !guide.reachable[child].contains(pruned)
+                    && parent != pruned
+                    && !graph[sibling].parents.contains(&grandparent)
+                    && (child, parent) != (sibling, pruned)
+            }
+            None => false,
+        },
+    }
+}
+
+// the probability of `propose` drawing the proposal, once its kind is drawn
+fn proposal_probability(graph: &Graph, proposal: Proposal) -> f64 {
+    let internal = internal_nodes(graph).count() as f64;
+    let non_root = non_root_nodes(graph).count() as f64;
+    let edges = f64::from(graph.edge_count());
+    match proposal {
+        Proposal::SetAminoAcid(_, position, _) => {
+            1.0 / (internal
+                * graph.alignment().positions.len() as f64
+                * residues(graph, position).len() as f64)
+        }
+        Proposal::MoveEdge(..) => 1.0 / (edges * internal),
+        Proposal::AddEdge(..) => 1.0 / (non_root * internal),
+        Proposal::RemoveEdge(..) => 1.0 / edges,
+        Proposal::Regraft(..) => 1.0 / (non_root * edges),
+    }
+}
+
+fn reversed(graph: &Graph, proposal: Proposal) -> Proposal {
+    match proposal {
+        Proposal::SetAminoAcid(node, position, _) => {
+            Proposal::SetAminoAcid(node, position, graph[node].amino_acids[position].amino_acid)
+        }
+        Proposal::MoveEdge(source, destination, new_destination) => {
+            Proposal::MoveEdge(source, new_destination, destination)
+        }
+        Proposal::AddEdge(source, destination) => Proposal::RemoveEdge(source, destination),
+        Proposal::RemoveEdge(source, destination) => Proposal::AddEdge(source, destination),
+        Proposal::Regraft(node, ..) => {
+            let (pruned, sibling) = pruned(graph, node).unwrap();
+            Proposal::Regraft(node, sibling, graph[pruned].parents[0])
+        }
+    }
+}
+
+fn apply(graph: &mut Graph, proposal: Proposal) {
+    match proposal {
+        Proposal::SetAminoAcid(node, position, amino_acid) => {
+            graph.set_amino_acid(node, position, amino_acid)
+        }
+        Proposal::MoveEdge(source, destination, new_destination) => {
+            graph.remove_edge(source, destination);
+            graph.add_edge(source, new_destination);
+        }
+        Proposal::AddEdge(source, destination) => graph.add_edge(source, destination),
+        Proposal::RemoveEdge(source, destination) => graph.remove_edge(source, destination),
+        Proposal::Regraft(node, child, parent) => {
+            let (pruned, sibling) = pruned(graph, node).unwrap();
+            let grandparent = graph[pruned].parents[0];
+            graph.remove_edge(sibling, pruned);
+            graph.remove_edge(pruned, grandparent);
+            graph.add_edge(sibling, grandparent);
+            graph.remove_edge(child, parent);
+            graph.add_edge(child, pruned);
+            graph.add_edge(pruned, parent);
+        }
+    }
+}
+
+impl<'a> Chain<'a> {
+    pub fn new(graph: Graph<'a>, random: Random) -> Chain<'a> {
+        Chain::heated(graph, random, 1.0)
+    }
+
+    pub fn heated(graph: Graph<'a>, random: Random, heat: f64) -> Chain<'a> {
+        Chain {
+            guide: Guide::new(&graph),
+            graph,
+            random,
+            heat,
+            proposed: 0,
+            accepted: 0,
+        }
+    }
+
+    pub fn graph(&mut self) -> &mut Graph<'a> {
+        &mut self.graph
+    }
+
+    /// Proposes a move and accepts it with the Metropolis–Hastings
+    /// probability, returning whether it was accepted.
+    pub fn step(&mut self) -> bool {
+        self.proposed += 1;
+        let proposal = propose(&self.graph, &mut self.random);
+        if !is_allowed(&self.graph, &self.guide, proposal) {
+            return false;
+        }
+
+        let mut new_graph = self.graph.clone();
+        apply(&mut new_graph, proposal);
+        let new_guide = Guide::new(&new_graph);
+        let reverse = reversed(&self.graph, proposal);
+        if !is_allowed(&new_graph, &new_guide, reverse) {
+            return false;
+        }
+
+        let bits = (new_graph.probability().log2() - self.graph.probability().log2()).raw()
+            / self.heat
+            + (proposal_probability(&new_graph, reverse)
+                / proposal_probability(&self.graph, proposal))
+            .log2();
+        if bits >= 0.0 || self.random.gen::<f64>().log2() < bits {
+            self.graph = new_graph;
+            self.guide = new_guide;
+            self.accepted += 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Metropolis-coupled chains at a ladder of heats, which propose swapping
+/// their graphs between neighbouring heats. The first chain is unheated and
+/// samples the posterior.
+pub struct Tempering<'a> {
+    pub chains: Vec<Chain<'a>>,
+    random: Random,
+    // by the lower of the two neighbouring chains
+    pub swaps_proposed: Vec<u64>,
+    pub swaps_accepted: Vec<u64>,
+}
+
+/// Heats rising geometrically from 1 to `max_heat`.
+pub fn heat_ladder(chains: usize, max_heat: f64) -> Vec<f64> {
+    (0..chains)
+        .map(|index| match chains {
+            1 => 1.0,
+            _ => max_heat.powf(index as f64 / (chains - 1) as f64),
+        })
+        .collect()
+}
+
+impl<'a> Tempering<'a> {
+    pub fn new(graph: Graph<'a>, mut random: Random, heats: &[f64]) -> Tempering<'a> {
+        let chains = heats
+            .iter()
+            .map(|&heat| Chain::heated(graph.clone(), Random::seed_from_u64(random.gen()), heat))
+            .collect();
+        Tempering {
+            chains,
+            random,
+            swaps_proposed: vec![0; heats.len().saturating_sub(1)],
+            swaps_accepted: vec![0; heats.len().saturating_sub(1)],
+        }
+    }
+
+    pub fn cold(&mut self) -> &mut Chain<'a> {
+        &mut self.chains[0]
+    }
+
+    /// Steps every chain in parallel, calling `cold_step` with the unheated
+    /// chain's graph after each of its steps, then proposes one swap.
+    pub fn run(
+        &mut self,
+        steps: u64,
+        mut cold_step: impl FnMut(&mut Graph<'a>) -> Result<()> + Send,
+    ) -> Result<()> {
+        let (cold, heated) = self.chains.split_first_mut().unwrap();
+        let (result, ()) = rayon::join(
+            || -> Result<()> {
+                for _ in 0..steps {
+                    cold.step();
+                    cold_step(&mut cold.graph)?;
+                }
+                Ok(())
+            },
+            || {
+                heated.par_iter_mut().for_each(|chain| {
+                    for _ in 0..steps {
+                        chain.step();
+                    }
+                })
+            },
+        );
+        result?;
+        self.swap();
+        Ok(())
+    }
+
+    fn swap(&mut self) {
+        if self.chains.len() < 2 {
+            return;
+        }
+        let index = self.random.gen_range(0..self.chains.len() - 1);
+        self.swaps_proposed[index] += 1;
+
+        let (lower, upper) = self.chains.split_at_mut(index + 1);
+        let (lower, upper) = (&mut lower[index], &mut upper[0]);
+        let bits = (upper.graph.probability().log2() - lower.graph.probability().log2()).raw()
+            * (1.0 / lower.heat - 1.0 / upper.heat);
+        if bits >= 0.0 || self.random.gen::<f64>().log2() < bits {
+            std::mem::swap(&mut lower.graph, &mut upper.graph);
+            std::mem::swap(&mut lower.guide, &mut upper.guide);
+            self.swaps_accepted[index] += 1;
+        }
+    }
+}
+
+/// One line of `samples.log`.
+#[derive(Serialize, Deserialize)]
+pub struct Sample {
+    pub iteration: u64,
+    pub probability: Log,
+    pub nodes: indexmap::IndexMap<String, crate::graph::ExportedNode>,
+}
+
+#[derive(Serialize)]
+pub struct EdgeSupport {
+    pub child: String,
+    pub parent: String,
+    pub support: f64,
+}
+
+#[derive(Serialize)]
+pub struct ResidueSupport {
+    pub node: String,
+    pub column: usize,
+    pub support: indexmap::IndexMap<char, f64>,
+}
+
+#[derive(Serialize)]
+pub struct SupportReport {
+    pub samples: usize,
+    pub edges: Vec<EdgeSupport>,
+    pub residues: Vec<ResidueSupport>,
+}
+
+/// Counts how often edges and ancestral residues appear across samples.
+#[derive(Default)]
+pub struct Support {
+    samples: usize,
+    edges: indexmap::IndexMap<(String, String), usize>,
+    // by node, column and residue
+    residues: indexmap::IndexMap<String, Vec<indexmap::IndexMap<char, usize>>>,
+}
+
+impl Support {
+    pub fn add(&mut self, sample: &Sample) {
+        self.samples += 1;
+        for (name, node) in &sample.nodes {
+            for parent in &node.parents {
+                *self
+                    .edges
+                    .entry((name.clone(), parent.clone()))
+                    .or_default() += 1;
+            }
+            if node.kind == crate::graph::ExportedNodeKind::Leaf {
+                continue;
+            }
+            let columns = self
+                .residues
+                .entry(name.clone())
+                .or_insert_with(|| vec![Default::default(); node.amino_acids.len()]);
+            for (counts, residue) in columns.iter_mut().zip(node.amino_acids.chars()) {
+                *counts.entry(residue).or_default() += 1;
+            }
+        }
+    }
+
+    pub fn report(&self) -> SupportReport {
+        let fraction = |count: usize| count as f64 / self.samples as f64;
+        SupportReport {
+            samples: self.samples,
+            edges: self
+                .edges
+                .iter()
+                .map(|((child, parent), &count)| EdgeSupport {
+                    child: child.clone(),
+                    parent: parent.clone(),
+                    support: fraction(count),
+                })
+                .sorted_by(|lhs, rhs| rhs.support.partial_cmp(&lhs.support).unwrap())
+                .collect(),
+            residues: self
+                .residues
+                .iter()
+                .flat_map(|(node, columns)| {
+                    columns
+                        .iter()
+                        .enumerate()
+                        .map(move |(column, counts)| ResidueSupport {
+                            node: node.clone(),
+                            column,
+                            support: counts
+                                .iter()
+                                .sorted_by_key(|(_, &count)| std::cmp::Reverse(count))
+                                .map(|(&residue, &count)| (residue, fraction(count)))
+                                .collect(),
+                        })
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_chain() -> Result<()> {
+        let model = crate::amino_acids::read_paml_matrix(std::io::Cursor::new(include_bytes!(
+            "./BLOSUM62.paml"
+        )))?;
+        let alignment = crate::alignment::read_alignment(
+            std::io::Cursor::new(">a\nAWTKC\n>b\nAWSRC\n>c\nCWSRD\n>d\nCFSRD\n>e\nAFTKC\n"),
+            &model,
+        )?;
+        let mut graph = Graph::new(&model, &alignment);
+        crate::optimization::nn_join(&mut graph);
+        let node_count = graph.nodes().len();
+
+        let mut chain = Chain::new(graph, Random::seed_from_u64(7));
+        let mut support = Support::default();
+        for iteration in 0..300 {
+            chain.step();
+            let graph = chain.graph();
+            assert_eq!(graph.nodes().len(), node_count);
+            support.add(&Sample {
+                iteration,
+                probability: graph.probability(),
+                nodes: graph.exported(),
+            });
+        }
+        assert!(chain.accepted > 0 && chain.accepted < chain.proposed);
+
+        // the cached probability of the chain's graph is the one recomputed
+        // from scratch
+        let graph = chain.graph();
+        let probability = graph.probability();
+        let mut rebuilt =
+            Graph::from_exported(&model, &alignment, graph.parameter(), &graph.exported())?;
+        assert!((rebuilt.probability().log2() - probability.log2()).abs() < 1e-6);
+        graph.validate();
+
+        let report = support.report();
+        assert_eq!(report.samples, 300);
+        for edge in &report.edges {
+            assert!(edge.support > 0.0 && edge.support <= 1.0);
+        }
+        for residues in &report.residues {
+            let total: f64 = residues.support.values().sum();
+            assert!((total - 1.0).abs() < 1e-9);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_tempering() -> Result<()> {
+        let model = crate::amino_acids::read_paml_matrix(std::io::Cursor::new(include_bytes!(
+            "./BLOSUM62.paml"
+        )))?;
+        let alignment = crate::alignment::read_alignment(
+            std::io::Cursor::new(">a\nAWTKC\n>b\nAWSRC\n>c\nCWSRD\n>d\nCFSRD\n>e\nAFTKC\n"),
+            &model,
+        )?;
+        let mut graph = Graph::new(&model, &alignment);
+        crate::optimization::nn_join(&mut graph);
+
+        let heats = heat_ladder(4, 8.0);
+        assert_eq!(heats[0], 1.0);
+        assert!((heats[3] - 8.0).abs() < 1e-9);
+        assert_eq!(heat_ladder(1, 8.0), vec![1.0]);
+
+        let mut tempering = Tempering::new(graph, Random::seed_from_u64(7), &heats);
+        let mut cold_steps = 0;
+        for _ in 0..50 {
+            tempering.run(5, |_| {
+                cold_steps += 1;
+                Ok(())
+            })?;
+        }
+        assert_eq!(cold_steps, 250);
+        assert_eq!(tempering.swaps_proposed.iter().sum::<u64>(), 50);
+        assert!(tempering.swaps_accepted.iter().sum::<u64>() > 0);
+        for chain in &mut tempering.chains {
+            assert_eq!(chain.proposed, 250);
+            chain.graph().validate();
+        }
+        Ok(())
+    }
+}
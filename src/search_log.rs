@@ -0,0 +1,267 @@
+use crate::optimization::{self, moves::GraphMove, moves::MoveLog, moves::MoveLogKind};
+use crate::prelude::*;
+use std::io::{BufRead, Write};
+use std::path::Path;
+
+pub const MOVES_LOG: &str = "moves.log";
+pub const ROUNDS_LOG: &str = "rounds.log";
+
+fn timestamp() -> f64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs_f64())
+        .unwrap_or(0.0)
+}
+
+/// A line of `moves.log`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MoveRecord {
+    // in the syntax GraphMove parses, absent for refits
+    #[serde(rename = "move")]
+    pub the_move: Option<String>,
+    pub probability: Log,
+    pub kind: MoveLogKind,
+    // neither is set for the hill climbing before the buckets are made
+    pub bucket: Option<usize>,
+    pub round: Option<u32>,
+    // how many records had been written when the move's bucket branched from
+    // the best graph
+    pub base: usize,
+    pub timestamp: f64,
+    pub node_count: usize,
+    pub edge_count: u32,
+}
+
+impl MoveRecord {
+    pub fn new(
+        made_move: &MoveLog,
+        bucket: Option<usize>,
+        round: Option<u32>,
+        base: usize,
+    ) -> MoveRecord {
+        MoveRecord {
+            the_move: made_move.the_move.map(|the_move| the_move.to_string()),
+            probability: made_move.probability,
+            kind: made_move.kind,
+            bucket,
+            round,
+            base,
+            timestamp: timestamp(),
+            node_count: made_move.node_count,
+            edge_count: made_move.edge_count,
+        }
+    }
+}
+
+/// A line of `rounds.log`, written at the start of each round.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RoundRecord {
+    pub round: u32,
+    pub probability: Log,
+    pub prior: Log,
+    pub likelihood: Log,
+    pub classification: String,
+    pub timestamp: f64,
+    pub node_count: usize,
+    pub edge_count: u32,
+}
+
+impl RoundRecord {
+    pub fn new(graph: &mut Graph, round: u32) -> RoundRecord {
+        RoundRecord {
+            round,
+            probability: graph.probability(),
+            prior: graph.prior(),
+            likelihood: graph.likelihood(),
+            classification: graph.classify().to_string(),
+            timestamp: timestamp(),
+            node_count: graph.nodes().len(),
+            edge_count: graph.edge_count(),
+        }
+    }
+}
+
+pub fn write_record(write: &mut impl Write, record: &impl Serialize) -> Result<()> {
+    serde_json::to_writer(&mut *write, record)?;
+    writeln!(write)?;
+    Ok(())
+}
+
+pub fn read_records<T: serde::de::DeserializeOwned>(read: impl BufRead) -> Result<Vec<T>> {
+    read.lines()
+        .filter(|line| !matches!(line, Ok(line) if line.trim().is_empty()))
+        .map(|line| Ok(serde_json::from_str(&line?)?))
+        .collect()
+}
+
+pub fn count_records(path: &Path) -> Result<usize> {
+    let read = std::io::BufReader::new(std::fs::File::open(path)?);
+    let mut count = 0;
+    for line in read.lines() {
+        if !line?.trim().is_empty() {
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+impl MoveRecord {
+    // records written together when a bucket was accepted share a key
+    fn batch(&self) -> (Option<usize>, Option<u32>, usize) {
+        (self.bucket, self.round, self.base)
+    }
+
+    fn apply(&self, graph: &mut Graph) -> Result<()> {
+        match &self.the_move {
+            None => {
+                optimization::optimize_groups(graph);
+                optimization::optimize_parameter(graph);
+                graph.compact();
+            }
+            Some(text) => {
+                let the_move: GraphMove = text.parse()?;
+                if !the_move.is_valid(graph) {
+                    bail!("{} cannot be applied", text);
+                }
+                optimization::moves::apply_mutation(graph, vec![the_move]);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Re-applies logged moves to the graph the search started from. After each
+/// record, `step` is given the number of records replayed and the graph the
+/// record was logged for, and replay stops once it returns false.
+pub fn replay<'a>(
+    start: Graph<'a>,
+    records: &[MoveRecord],
+    mut step: impl FnMut(usize, &MoveRecord, &mut Graph<'a>) -> bool,
+) -> Result<Graph<'a>> {
+    let mut last_use = FnvHashMap::default();
+    for (index, record) in records.iter().enumerate() {
+        last_use.insert(record.base, index);
+    }
+
+    // the best graph after each accepted batch that a later bucket branched from
+    let mut states = FnvHashMap::default();
+    states.insert(0, start.clone());
+    let mut graph = start;
+    for (index, record) in records.iter().enumerate() {
+        if index == 0 || records[index - 1].batch() != record.batch() {
+            graph = match states.get(&record.base) {
+                Some(state) => state.clone(),
+                None => bail!(
+                    "Record {} branches from record {}, which did not end a batch",
+                    index + 1,
+                    record.base
+                ),
+            };
+        }
+        record
+            .apply(&mut graph)
+            .map_err(|error| eyre!("Record {}: {}", index + 1, error))?;
+
+        let ends_batch = index + 1 == records.len() || records[index + 1].batch() != record.batch();
+        if ends_batch && last_use.contains_key(&(index + 1)) {
+            states.insert(index + 1, graph.clone());
+        }
+        states.retain(|base, _| matches!(last_use.get(base), Some(&last) if last > index));
+
+        if !step(index + 1, record, &mut graph) {
+            break;
+        }
+    }
+    Ok(graph)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_move_records() -> Result<()> {
+        let moves = [
+            GraphMove::Refactor(NodeId(3), NodeId(7)),
+            GraphMove::AddEdge(NodeId(3), NodeId(7)),
+            GraphMove::ChangeEdge(NodeId(1), NodeId(2), NodeId(12)),
+            GraphMove::SetAminoAcid(NodeId(12), PositionIndex(40), AminoAcid::Asn),
+            GraphMove::FloodFill(NodeId(5), PositionIndex(0), AminoAcid::Gap),
+        ];
+
+        let mut written = Vec::new();
+        for (index, &the_move) in moves.iter().enumerate() {
+            let made_move = MoveLog {
+                the_move: Some(the_move),
+                probability: Log::pow2(n64(-12.5)),
+                kind: MoveLogKind::Climbing,
+                node_count: 10,
+                edge_count: 11,
+            };
+            write_record(
+                &mut written,
+                &MoveRecord::new(&made_move, Some(index), Some(2), 0),
+            )?;
+        }
+
+        let text = String::from_utf8(written.clone())?;
+        assert!(text.lines().next().unwrap().starts_with(
+            r#"{"move":"refactor:N3,N7","probability":-12.5,"kind":"climbing","bucket":0,"round":2,"#
+        ));
+
+        let records: Vec<MoveRecord> = read_records(std::io::Cursor::new(written))?;
+        assert_eq!(records.len(), moves.len());
+        for (record, &the_move) in records.iter().zip(moves.iter()) {
+            assert_eq!(
+                record.the_move.as_ref().unwrap().parse::<GraphMove>()?,
+                the_move
+            );
+            assert_eq!(record.kind, MoveLogKind::Climbing);
+            assert_eq!((record.node_count, record.edge_count), (10, 11));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_replay() -> Result<()> {
+        let model = crate::amino_acids::read_paml_matrix(std::io::Cursor::new(include_bytes!(
+            "./BLOSUM62.paml"
+        )))?;
+        let alignment = crate::alignment::read_alignment(
+            std::io::Cursor::new(">a\nAWTKC\n>b\nAWSRC\n>c\nCWSRD\n>d\nCFSRD\n>e\nAFTKC\n"),
+            &model,
+        )?;
+        let mut random = <Random as rand::SeedableRng>::seed_from_u64(7);
+        let mut start = Graph::new(&model, &alignment);
+        optimization::nn_join(&mut start);
+
+        // two buckets branch from the start, the second is accepted after
+        // the first, and a third branches from the first's result
+        let mut records = Vec::new();
+        let mut first = start.clone();
+        for made_move in optimization::moves::shuffle(&mut first, &mut random, 3) {
+            records.push(MoveRecord::new(&made_move, Some(0), Some(0), 0));
+        }
+        let mut second = start.clone();
+        for made_move in optimization::moves::shuffle(&mut second, &mut random, 2) {
+            records.push(MoveRecord::new(&made_move, Some(1), Some(0), 0));
+        }
+        let mut third = first.clone();
+        for made_move in optimization::moves::shuffle(&mut third, &mut random, 2) {
+            records.push(MoveRecord::new(&made_move, Some(0), Some(1), 3));
+        }
+
+        let mut replayed = Vec::new();
+        let mut graph = replay(start.clone(), &records, |index, record, graph| {
+            assert_eq!(graph.probability(), record.probability);
+            replayed.push(index);
+            true
+        })?;
+        assert_eq!(replayed, (1..=7).collect_vec());
+        assert_eq!(graph.probability(), third.probability());
+
+        let mut graph = replay(start, &records, |index, _, _| index != 5)?;
+        assert_eq!(graph.probability(), records[4].probability);
+        Ok(())
+    }
+}
@@ -0,0 +1,387 @@
+//! Alignments evolved down a known graph, to check how much of it a search
+//! recovers.
+
+use crate::amino_acids::{AminoAcidModel, SiteRates};
+use crate::clusters::ExportedNodes;
+use crate::graph::{ExportedNode, ExportedNodeKind, Inheritance, NodeAminoAcid};
+use crate::prelude::*;
+use fnv::FnvHashSet;
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::seq::SliceRandom;
+use std::collections::BTreeSet;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Evolution {
+    pub parameter: R64,
+    pub rates: SiteRates,
+    // per node and column, of a gap becoming a residue and the other way
+    pub insert_rate: f64,
+    pub delete_rate: f64,
+    // columns at the root, before any that end up gaps in every sequence
+    // are dropped
+    pub length: usize,
+}
+
+/// A random tree over `sequences` sequences named s0, s1 and so on, joined
+/// pairwise, with `extra_edges` edges then added to ancestors that are not
+/// already ancestors of the child. Ancestral residues are left empty.
+pub fn random_graph(
+    sequences: usize,
+    extra_edges: usize,
+    random: &mut Random,
+) -> Result<ExportedNodes> {
+    if sequences < 2 {
+        bail!("A graph needs at least 2 sequences");
+    }
+
+    // the root, then the leaves, then the ancestors in the order joined
+    let mut parents = vec![Vec::new(); sequences + 1];
+    let mut unjoined = (1..=sequences).collect_vec();
+    while unjoined.len() > 2 {
+        let ancestor = parents.len();
+        parents.push(Vec::new());
+        for _ in 0..2 {
+            let child = unjoined.swap_remove(random.gen_range(0..unjoined.len()));
+            parents[child].push(ancestor);
+        }
+        unjoined.push(ancestor);
+    }
+    for child in unjoined {
+        parents[child].push(0);
+    }
+
+    let is_leaf = |node: usize| (1..=sequences).contains(&node);
+    for _ in 0..extra_edges {
+        let ancestors = ancestors(&parents);
+        let candidates = (1..parents.len())
+            .cartesian_product((0..parents.len()).filter(|&node| !is_leaf(node)))
+            .filter(|&(child, parent)| {
+                !ancestors[child].contains(&parent) && !ancestors[parent].contains(&child)
+            })
+            .collect_vec();
+        let &(child, parent) = candidates.choose(random).ok_or_else(|| {
+            eyre!(
+                "No room for {} extra edges among {} sequences",
+                extra_edges,
+                sequences
+            )
+        })?;
+        parents[child].push(parent);
+    }
+
+    Ok(parents
+        .iter()
+        .enumerate()
+        .map(|(node, node_parents)| {
+            (
+                format!("N{}", node),
+                ExportedNode {
+                    kind: match node {
+                        0 => ExportedNodeKind::Root,
+                        _ if is_leaf(node) => ExportedNodeKind::Leaf,
+                        _ => ExportedNodeKind::Other,
+                    },
+                    sequence_id: if is_leaf(node) {
+                        Some(format!("s{}", node - 1))
+                    } else {
+                        None
+                    },
+                    parents: node_parents
+                        .iter()
+                        .sorted()
+                        .map(|parent| format!("N{}", parent))
+                        .collect(),
+                    amino_acids: String::new(),
+                },
+            )
+        })
+        .collect())
+}
+
+// each node with itself and everything above it
+fn ancestors(parents: &[Vec<usize>]) -> Vec<BTreeSet<usize>> {
+    fn visit(node: usize, parents: &[Vec<usize>], found: &mut Vec<Option<BTreeSet<usize>>>) {
+        if found[node].is_some() {
+            return;
+        }
+        let mut ancestors = BTreeSet::new();
+        ancestors.insert(node);
+        for &parent in &parents[node] {
+            visit(parent, parents, found);
+            ancestors.extend(found[parent].as_ref().unwrap());
+        }
+        found[node] = Some(ancestors);
+    }
+
+    let mut found = vec![None; parents.len()];
+    for node in 0..parents.len() {
+        visit(node, parents, &mut found);
+    }
+    found.into_iter().map(Option::unwrap).collect()
+}
+
+// parents before children
+fn topological_order(nodes: &ExportedNodes) -> Result<Vec<&str>> {
+    for (name, node) in nodes {
+        if let Some(parent) = node
+            .parents
+            .iter()
+            .find(|&parent| !nodes.contains_key(parent))
+        {
+            bail!("Node {} has an unknown parent {}", name, parent);
+        }
+        match node.kind {
+            ExportedNodeKind::Root if !node.parents.is_empty() => {
+                bail!("Root {} has parents", name)
+            }
+            ExportedNodeKind::Leaf | ExportedNodeKind::Other if node.parents.is_empty() => {
+                bail!("Node {} has no parents", name)
+            }
+            ExportedNodeKind::Leaf if node.sequence_id.is_none() => {
+                bail!("Leaf {} has no sequence", name)
+            }
+            _ => {}
+        }
+    }
+
+    let mut order = Vec::new();
+    let mut released = FnvHashSet::default();
+    while order.len() < nodes.len() {
+        let before = order.len();
+        for (name, node) in nodes {
+            if !released.contains(name.as_str())
+                && node
+                    .parents
+                    .iter()
+                    .all(|parent| released.contains(parent.as_str()))
+            {
+                order.push(name.as_str());
+                released.insert(name.as_str());
+            }
+        }
+        if order.len() == before {
+            bail!("The graph has a cycle");
+        }
+    }
+    Ok(order)
+}
+
+// the highest parent wins, as in scoring, which makes parents tied on height
+// with different residues impossible
+fn inherit(parents: &[(AminoAcid, u8)]) -> Option<(AminoAcid, u8)> {
+    let Inheritance(amino_acid, height) = parents.iter().fold(
+        Inheritance(AminoAcid::Gap, 0),
+        |inheritance, &(amino_acid, height)| {
+            inheritance.update(NodeAminoAcid {
+                inherited: None,
+                amino_acid,
+                pending: false,
+                height,
+            })
+        },
+    );
+    if amino_acid == AminoAcid::Unknown {
+        None
+    } else {
+        Some((amino_acid, height))
+    }
+}
+
+const COLUMN_ATTEMPTS: usize = 1000;
+
+/// Evolves residues down the graph from a root drawn from the model's
+/// frequencies, returning the graph with the residues of every node.
+pub fn evolve(
+    model: &AminoAcidModel,
+    topology: &ExportedNodes,
+    evolution: &Evolution,
+    random: &mut Random,
+) -> Result<ExportedNodes> {
+    let order = topological_order(topology)?;
+    if topology
+        .values()
+        .filter(|node| node.kind == ExportedNodeKind::Root)
+        .count()
+        != 1
+    {
+        bail!("The graph needs exactly one root");
+    }
+
+    let frequencies = WeightedIndex::new(model.frequencies())?;
+    let parameterized = model.parameterize_with_rates(evolution.parameter, evolution.rates);
+    let categories = WeightedIndex::new(
+        parameterized
+            .categories
+            .iter()
+            .map(|(weight, _)| weight.raw().raw()),
+    )?;
+    let transitions = parameterized
+        .categories
+        .iter()
+        .map(|(_, matrix)| {
+            (0..20)
+                .map(|from| WeightedIndex::new((0..20).map(|to| matrix[(from, to)].raw().raw())))
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // a column is evolved again whenever a node ends up inheriting from
+    // parents tied on height, so that the graph can be scored
+    let evolve_column = |random: &mut Random| {
+        let transitions = &transitions[categories.sample(random)];
+        let mut column: FnvHashMap<&str, (AminoAcid, u8)> = FnvHashMap::default();
+        for &name in &order {
+            let node = &topology[name];
+            let residue = if node.kind == ExportedNodeKind::Root {
+                // inherited from a gap, as scoring sees it, so one change high
+                (AminoAcid::from_index(frequencies.sample(random)), 1)
+            } else {
+                let parents = node
+                    .parents
+                    .iter()
+                    .map(|parent| column[parent.as_str()])
+                    .collect_vec();
+                let (inherited, height) = inherit(&parents)?;
+                let amino_acid = if inherited == AminoAcid::Gap {
+                    if random.gen_bool(evolution.insert_rate) {
+                        AminoAcid::from_index(frequencies.sample(random))
+                    } else {
+                        AminoAcid::Gap
+                    }
+                } else if random.gen_bool(evolution.delete_rate) {
+                    AminoAcid::Gap
+                } else {
+                    AminoAcid::from_index(transitions[inherited.as_index().unwrap()].sample(random))
+                };
+                if amino_acid == inherited {
+                    (amino_acid, height)
+                } else {
+                    (amino_acid, height.saturating_add(1))
+                }
+            };
+            column.insert(name, residue);
+        }
+        Some(column)
+    };
+
+    let mut residues: FnvHashMap<&str, Vec<AminoAcid>> = FnvHashMap::default();
+    for _ in 0..evolution.length {
+        let column = (0..COLUMN_ATTEMPTS)
+            .find_map(|_| evolve_column(random))
+            .ok_or_else(|| {
+                eyre!(
+                    "A node inherited from tied parents in {} attempts at a column",
+                    COLUMN_ATTEMPTS
+                )
+            })?;
+        for (name, (amino_acid, _)) in column {
+            residues.entry(name).or_default().push(amino_acid);
+        }
+    }
+
+    // a column no sequence has a residue in would not be in an alignment
+    let columns = (0..evolution.length)
+        .filter(|&column| {
+            topology.iter().any(|(name, node)| {
+                node.kind == ExportedNodeKind::Leaf
+                    && residues[name.as_str()][column] != AminoAcid::Gap
+            })
+        })
+        .collect_vec();
+
+    Ok(topology
+        .iter()
+        .map(|(name, node)| {
+            let node_residues = &residues[name.as_str()];
+            (
+                name.clone(),
+                ExportedNode {
+                    kind: node.kind,
+                    sequence_id: node.sequence_id.clone(),
+                    parents: node.parents.clone(),
+                    amino_acids: columns
+                        .iter()
+                        .map(|&column| char::from(node_residues[column].as_u8()))
+                        .collect(),
+                },
+            )
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_random_graph() -> Result<()> {
+        let mut random = Random::seed_from_u64(3);
+        let nodes = random_graph(6, 2, &mut random)?;
+        assert_eq!(nodes.len(), 11);
+        assert_eq!(
+            nodes.values().map(|node| node.parents.len()).sum::<usize>(),
+            12
+        );
+        assert_eq!(topological_order(&nodes)?[0], "N0");
+        assert!(random_graph(1, 0, &mut random).is_err());
+        // three sequences leave room for a single extra edge
+        assert!(random_graph(3, 2, &mut random).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_inherit() {
+        assert_eq!(
+            inherit(&[(AminoAcid::Ala, 0), (AminoAcid::Arg, 1)]),
+            Some((AminoAcid::Arg, 1))
+        );
+        assert_eq!(
+            inherit(&[(AminoAcid::Ala, 2), (AminoAcid::Ala, 2)]),
+            Some((AminoAcid::Ala, 2))
+        );
+        assert_eq!(inherit(&[(AminoAcid::Ala, 1), (AminoAcid::Arg, 1)]), None);
+    }
+
+    #[test]
+    fn test_evolve() -> Result<()> {
+        let model = crate::amino_acids::read_paml_matrix(std::io::Cursor::new(include_bytes!(
+            "./BLOSUM62.paml"
+        )))?;
+        let mut random = Random::seed_from_u64(3);
+        let topology = random_graph(5, 1, &mut random)?;
+
+        // nothing changes at a parameter of zero
+        let still = Evolution {
+            parameter: r64(0.0),
+            rates: SiteRates::default(),
+            insert_rate: 0.0,
+            delete_rate: 0.0,
+            length: 40,
+        };
+        let nodes = evolve(&model, &topology, &still, &mut random)?;
+        assert!(nodes
+            .values()
+            .all(|node| node.amino_acids == nodes["N0"].amino_acids));
+        assert_eq!(nodes["N0"].amino_acids.len(), 40);
+
+        let moving = Evolution {
+            parameter: r64(0.05),
+            delete_rate: 0.2,
+            insert_rate: 0.2,
+            ..still
+        };
+        let nodes = evolve(&model, &topology, &moving, &mut random)?;
+        let length = nodes["N0"].amino_acids.len();
+        assert!(length <= 40);
+        assert!(nodes.values().all(|node| node.amino_acids.len() == length));
+        assert!(nodes.values().any(|node| node.amino_acids.contains('-')));
+
+        let mut cyclic = random_graph(3, 0, &mut random)?;
+        cyclic["N1"].kind = ExportedNodeKind::Other;
+        cyclic["N1"].parents = vec!["N4".to_string()];
+        cyclic["N4"].parents = vec!["N1".to_string()];
+        assert!(evolve(&model, &cyclic, &still, &mut random).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,52 @@
+use crate::amino_acids::{self, AminoAcidModel};
+use crate::compression;
+use crate::prelude::*;
+use std::path::{Path, PathBuf};
+
+/// The model and alignment of a directory written by `infer`, from which the
+/// graph saved alongside them can be loaded.
+pub struct WorkingDirectory {
+    pub path: PathBuf,
+    pub model: AminoAcidModel,
+    pub alignment: Alignment,
+}
+
+impl WorkingDirectory {
+    pub fn open(path: &Path) -> Result<WorkingDirectory> {
+        let model = amino_acids::read_paml_matrix(compression::open(&path.join("matrix.paml"))?)?;
+        let alignment = read_alignment(compression::open(&path.join("alignment.fasta"))?, &model)?;
+        Ok(WorkingDirectory {
+            path: path.to_owned(),
+            model,
+            alignment,
+        })
+    }
+
+    pub fn parameter(&self) -> Result<R64> {
+        Ok(r64(std::fs::read_to_string(
+            self.path.join("parameter.txt"),
+        )?
+        .trim()
+        .parse()?))
+    }
+
+    /// Loads the saved graph with its fitted parameter and site rates.
+    pub fn graph(&self) -> Result<Graph<'_>> {
+        let exported = compression::read_graph(&self.path)?;
+        let mut graph =
+            Graph::from_exported(&self.model, &self.alignment, self.parameter()?, &exported)?;
+        graph.set_site_rates(amino_acids::read_site_rates(&self.path)?);
+        Ok(graph)
+    }
+
+    /// Saves a graph with its fitted parameter and site rates into the
+    /// directory at `path`.
+    pub fn save(path: &Path, graph: &Graph, compress_graph: bool) -> Result<()> {
+        compression::write_graph(path, graph, compress_graph)?;
+        std::fs::write(
+            path.join("parameter.txt"),
+            format!("{}", graph.parameter().raw()),
+        )?;
+        amino_acids::write_site_rates(path, &graph.site_rates())
+    }
+}